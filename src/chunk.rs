@@ -1,7 +1,10 @@
-#![allow(dead_code)]
-
 use crate::value::{Value, ValueArray};
 use num_enum::TryFromPrimitive;
+use std::cell::{Cell, OnceCell};
+
+/// Version of the bytecode instruction set, bumped whenever opcodes or their
+/// operands change.
+pub const BYTECODE_VERSION: u32 = 6;
 
 #[repr(u8)]
 #[derive(Copy, Clone, TryFromPrimitive)]
@@ -13,13 +16,159 @@ pub enum OpCode {
     Divide = 4,
     Negate = 5,
     Return = 6,
+    Nil = 7,
+    True = 8,
+    False = 9,
+    Not = 10,
+    Equal = 11,
+    Greater = 12,
+    Less = 13,
+    Pop = 14,
+    GetLocal = 15,
+    SetLocal = 16,
+    GetGlobal = 17,
+    DefineGlobal = 18,
+    SetGlobal = 19,
+    Print = 20,
+    Jump = 21,
+    JumpIfFalse = 22,
+    Loop = 23,
+    Call = 24,
+    /// OP_ADD after the VM has seen it add numbers enough times. The
+    /// compiler never emits it.
+    AddNumber = 25,
+    Object = 26,
+    GetProperty = 27,
+}
+
+/// The kind of an inline operand that follows an opcode in the bytecode.
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum OperandKind {
+    /// A one-byte index into the chunk's constant table.
+    Constant,
+    /// A one-byte unsigned value, such as a local variable's stack slot.
+    Byte,
+    /// A two-byte, big-endian distance to jump forward, measured from the end
+    /// of the instruction.
+    Jump,
+    /// A two-byte, big-endian distance to jump backward, measured from the
+    /// end of the instruction.
+    Loop,
+}
+
+impl OperandKind {
+    /// Number of bytes the operand occupies in the code stream.
+    pub fn size(self) -> usize {
+        match self {
+            OperandKind::Constant | OperandKind::Byte => 1,
+            OperandKind::Jump | OperandKind::Loop => 2,
+        }
+    }
+}
+
+/// Static metadata about an opcode, shared by the disassembler and the VM
+/// tracer so operand sizes are defined in exactly one place.
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct OpInfo {
+    pub name: &'static str,
+    pub operands: &'static [OperandKind],
+    /// Net change to the stack height when the operand is zero. Use
+    /// `stack_effect`, which accounts for the operand.
+    base_effect: i8,
+    /// How much each unit of the operand adds to the stack effect, for
+    /// instructions whose operand counts values.
+    effect_per_operand: i8,
+    /// How many values the instruction reads from the top of the stack.
+    pub inputs: u8,
+}
+
+impl OpInfo {
+    /// Total instruction length in bytes, including the opcode itself.
+    pub fn length(&self) -> usize {
+        1 + self.operands.iter().map(|kind| kind.size()).sum::<usize>()
+    }
+
+    /// Net change to the value stack height after the instruction runs with
+    /// `operand` as its first operand byte. Ignored by instructions whose
+    /// effect doesn't depend on it.
+    pub fn stack_effect(&self, operand: u8) -> i32 {
+        self.base_effect as i32 + self.effect_per_operand as i32 * operand as i32
+    }
+}
+
+impl OpCode {
+    pub fn info(self) -> OpInfo {
+        let (name, operands, base_effect, inputs): (_, &'static [OperandKind], _, _) = match self {
+            OpCode::Constant => ("OP_CONSTANT", &[OperandKind::Constant], 1, 0),
+            OpCode::Add => ("OP_ADD", &[], -1, 2),
+            OpCode::Subtract => ("OP_SUBTRACT", &[], -1, 2),
+            OpCode::Multiply => ("OP_MULTIPLY", &[], -1, 2),
+            OpCode::Divide => ("OP_DIVIDE", &[], -1, 2),
+            OpCode::Negate => ("OP_NEGATE", &[], 0, 1),
+            OpCode::Return => ("OP_RETURN", &[], -1, 1),
+            OpCode::Nil => ("OP_NIL", &[], 1, 0),
+            OpCode::True => ("OP_TRUE", &[], 1, 0),
+            OpCode::False => ("OP_FALSE", &[], 1, 0),
+            OpCode::Not => ("OP_NOT", &[], 0, 1),
+            OpCode::Equal => ("OP_EQUAL", &[], -1, 2),
+            OpCode::Greater => ("OP_GREATER", &[], -1, 2),
+            OpCode::Less => ("OP_LESS", &[], -1, 2),
+            OpCode::Pop => ("OP_POP", &[], -1, 1),
+            OpCode::GetLocal => ("OP_GET_LOCAL", &[OperandKind::Byte], 1, 0),
+            OpCode::SetLocal => ("OP_SET_LOCAL", &[OperandKind::Byte], 0, 1),
+            OpCode::GetGlobal => ("OP_GET_GLOBAL", &[OperandKind::Constant], 1, 0),
+            OpCode::DefineGlobal => ("OP_DEFINE_GLOBAL", &[OperandKind::Constant], -1, 1),
+            OpCode::SetGlobal => ("OP_SET_GLOBAL", &[OperandKind::Constant], 0, 1),
+            OpCode::Print => ("OP_PRINT", &[], -1, 1),
+            OpCode::Jump => ("OP_JUMP", &[OperandKind::Jump], 0, 0),
+            OpCode::JumpIfFalse => ("OP_JUMP_IF_FALSE", &[OperandKind::Jump], 0, 1),
+            OpCode::Loop => ("OP_LOOP", &[OperandKind::Loop], 0, 0),
+            // Also reads as many arguments as its operand says.
+            OpCode::Call => ("OP_CALL", &[OperandKind::Byte], 0, 1),
+            OpCode::AddNumber => ("OP_ADD_NUMBER", &[], -1, 2),
+            // Also reads a name and a value for each field its operand counts.
+            OpCode::Object => ("OP_OBJECT", &[OperandKind::Byte], 1, 0),
+            OpCode::GetProperty => ("OP_GET_PROPERTY", &[OperandKind::Constant], 0, 1),
+        };
+
+        let effect_per_operand = match self {
+            // Pops the arguments as well as the callee, and pushes the result.
+            OpCode::Call => -1,
+            // Pops a name and a value per field, and pushes the object.
+            OpCode::Object => -2,
+            _ => 0,
+        };
+
+        OpInfo {
+            name,
+            operands,
+            base_effect,
+            effect_per_operand,
+            inputs,
+        }
+    }
+}
+
+/// The first byte of code that came from `line`. Runs of bytes from the
+/// same line share one entry.
+#[derive(Copy, Clone, PartialEq, Debug)]
+struct LineStart {
+    offset: usize,
+    line: usize,
 }
 
 #[derive(Default)]
 pub struct Chunk {
-    pub code: Vec<u8>,
+    /// The VM rewrites some instructions while it runs them, so the code is
+    /// mutable through a shared reference.
+    code: Vec<Cell<u8>>,
     pub constants: ValueArray,
-    pub lines: Vec<usize>,
+    /// Source line of each byte of code, run-length encoded in offset order.
+    lines: Vec<LineStart>,
+    /// Counters the VM keeps about instructions, to decide when to rewrite
+    /// them, indexed by offset like `code`. Only allocated once one is
+    /// bumped, so code that never runs doesn't pay for them.
+    feedback: OnceCell<Box<[Cell<u8>]>>,
 }
 
 impl Chunk {
@@ -28,8 +177,80 @@ impl Chunk {
     }
 
     pub fn write(&mut self, byte: u8, line: usize) {
-        self.code.push(byte);
-        self.lines.push(line);
+        if self.lines.last().is_none_or(|last| last.line != line) {
+            self.lines.push(LineStart {
+                offset: self.code.len(),
+                line,
+            });
+        }
+        self.code.push(Cell::new(byte));
+        // The counters must cover all the code.
+        self.feedback.take();
+    }
+
+    pub fn len(&self) -> usize {
+        self.code.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.code.is_empty()
+    }
+
+    /// The byte at `offset`, which must be in the chunk.
+    pub fn byte(&self, offset: usize) -> u8 {
+        self.code[offset].get()
+    }
+
+    pub fn get_byte(&self, offset: usize) -> Option<u8> {
+        self.code.get(offset).map(Cell::get)
+    }
+
+    /// A copy of the code as it currently stands.
+    pub fn code(&self) -> Vec<u8> {
+        self.code.iter().map(Cell::get).collect()
+    }
+
+    /// Overwrites a byte that's already been written, such as a jump offset
+    /// that wasn't known yet.
+    pub fn patch(&mut self, offset: usize, byte: u8) {
+        self.code[offset].set(byte);
+    }
+
+    /// Replaces the opcode at `offset` with `op`, which must take the same
+    /// operands, and clears its feedback counter.
+    pub fn quicken(&self, offset: usize, op: OpCode) {
+        self.code[offset].set(op as u8);
+        if let Some(feedback) = self.feedback.get() {
+            feedback[offset].set(0);
+        }
+    }
+
+    /// Adds one to the feedback counter for the instruction at `offset` and
+    /// returns the new count, which stops at `u8::MAX`.
+    pub fn bump_feedback(&self, offset: usize) -> u8 {
+        let feedback = self
+            .feedback
+            .get_or_init(|| self.code.iter().map(|_| Cell::new(0)).collect());
+        let count = feedback[offset].get().saturating_add(1);
+        feedback[offset].set(count);
+        count
+    }
+
+    /// The source line the byte at `offset` was compiled from, or `None` if
+    /// `offset` is past the end of the code.
+    pub fn get_line(&self, offset: usize) -> Option<usize> {
+        if offset >= self.code.len() {
+            return None;
+        }
+
+        let run = self.lines.partition_point(|start| start.offset <= offset);
+        Some(self.lines[run - 1].line)
+    }
+
+    /// The line table as `(offset, line)` pairs: each run's first byte and
+    /// the line it and the bytes up to the next run came from.
+    pub fn line_runs(&self) -> impl Iterator<Item = (usize, usize)> + '_ {
+        self.lines.iter().map(|start| (start.offset, start.line))
     }
 
     pub fn add_constant(&mut self, value: Value) -> usize {
@@ -45,9 +266,10 @@ mod tests {
     #[test]
     fn init_chunk_test() {
         let chunk = Chunk::new();
-        assert_eq!(chunk.code.len(), 0);
+        assert_eq!(chunk.len(), 0);
         assert_eq!(chunk.constants.len(), 0);
         assert_eq!(chunk.lines.len(), 0);
+        assert_eq!(chunk.get_line(0), None);
     }
 
     #[test]
@@ -56,23 +278,84 @@ mod tests {
         chunk.write(OpCode::Return as u8, 123);
         chunk.write(OpCode::Constant as u8, 124);
 
-        assert_eq!(chunk.code.len(), 2);
+        assert_eq!(chunk.len(), 2);
         assert_eq!(chunk.lines.len(), 2);
 
-        assert_eq!(chunk.code[0], OpCode::Return as u8);
-        assert_eq!(chunk.lines[0], 123);
+        assert_eq!(chunk.byte(0), OpCode::Return as u8);
+        assert_eq!(chunk.get_line(0), Some(123));
 
-        assert_eq!(chunk.code[1], OpCode::Constant as u8);
-        assert_eq!(chunk.lines[1], 124);
+        assert_eq!(chunk.byte(1), OpCode::Constant as u8);
+        assert_eq!(chunk.get_line(1), Some(124));
+    }
+
+    #[test]
+    fn line_runs_test() {
+        let mut chunk = Chunk::new();
+        for line in [1, 1, 1, 2, 2, 5, 1] {
+            chunk.write(OpCode::Nil as u8, line);
+        }
+
+        assert_eq!(chunk.lines.len(), 4);
+        let lines: Vec<_> = (0..7)
+            .map(|offset| chunk.get_line(offset).unwrap())
+            .collect();
+        assert_eq!(lines, [1, 1, 1, 2, 2, 5, 1]);
+        assert_eq!(chunk.get_line(7), None);
+    }
+
+    #[test]
+    fn quicken_test() {
+        let mut chunk = Chunk::new();
+        chunk.write(OpCode::Add as u8, 1);
+        chunk.write(OpCode::Return as u8, 1);
+
+        assert_eq!(chunk.bump_feedback(0), 1);
+        assert_eq!(chunk.bump_feedback(0), 2);
+        assert_eq!(chunk.bump_feedback(1), 1);
+
+        chunk.quicken(0, OpCode::AddNumber);
+        assert_eq!(
+            chunk.code(),
+            [OpCode::AddNumber as u8, OpCode::Return as u8]
+        );
+        assert_eq!(chunk.bump_feedback(0), 1);
+
+        chunk.patch(1, OpCode::Nil as u8);
+        assert_eq!(chunk.get_byte(1), Some(OpCode::Nil as u8));
+        assert_eq!(chunk.get_byte(2), None);
     }
 
     #[test]
     fn add_constant_test() {
         let mut chunk: Chunk = Chunk::new();
-        let result = chunk.add_constant(4.3);
+        let result = chunk.add_constant(Value::Number(4.3));
 
         assert_eq!(result, 0);
         assert_eq!(chunk.constants.len(), 1);
-        assert_eq!(chunk.constants.at(0), 4.3);
+        assert_eq!(chunk.constants.at(0), Value::Number(4.3));
+    }
+
+    #[test]
+    fn op_info_test() {
+        let constant = OpCode::Constant.info();
+        assert_eq!(constant.name, "OP_CONSTANT");
+        assert_eq!(constant.length(), 2);
+        assert_eq!(constant.stack_effect(0), 1);
+
+        let add = OpCode::Add.info();
+        assert_eq!(add.name, "OP_ADD");
+        assert_eq!(add.length(), 1);
+        assert_eq!(add.stack_effect(0), -1);
+        assert_eq!(add.inputs, 2);
+
+        let jump = OpCode::JumpIfFalse.info();
+        assert_eq!(jump.name, "OP_JUMP_IF_FALSE");
+        assert_eq!(jump.length(), 3);
+        assert_eq!(jump.stack_effect(0), 0);
+
+        assert_eq!(OpCode::Call.info().stack_effect(0), 0);
+        assert_eq!(OpCode::Call.info().stack_effect(3), -3);
+        assert_eq!(OpCode::Object.info().stack_effect(0), 1);
+        assert_eq!(OpCode::Object.info().stack_effect(2), -3);
     }
 }
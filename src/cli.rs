@@ -0,0 +1,515 @@
+use rustlox::chunk::BYTECODE_VERSION;
+use rustlox::compiler::CompilerOptions;
+use rustlox::diagnostics::ErrorFormat;
+use rustlox::vm::TraceOptions;
+use std::fmt;
+use std::path::Path;
+
+pub const USAGE: &str = "\
+Usage: rustlox [options] [command] [path]
+
+Commands:
+  run <path>     Compile and run a script (the default when only a path is given)
+  tokens <path>  Print the tokens the scanner produces for a script
+  compile <path> [-o <out>]
+                 Compile a script to bytecode without running it, writing
+                 <out> (default: the path with a .loxc extension). `run`
+                 runs .loxc files like scripts
+  disassemble <path>
+                 Compile a script, or load a .loxc file, and print its
+                 bytecode without running it
+  repl           Start an interactive session (the default with no arguments)
+  explain-precedence
+                 Print the operator precedence table the compiler uses
+  gen-tests <dir>
+                 Write a .lox fixture exercising each opcode into a directory
+
+Options:
+  --prelude <path>  Run a script in the same VM before `run` or `repl`
+  --time            Report timings and instruction counts after running a
+                    script
+  --buffered        Buffer script output, writing it at the end of each run
+                    or when the script calls flush()
+  --strict          Make reading a possibly unassigned local a compile error
+                    instead of a warning
+  --error-format <text|json>
+                    How to print compile errors and warnings: as text (the
+                    default) or as one JSON object per line
+  --dump-bytecode   Print each function's bytecode after compiling it (also
+                    on when DEBUG_PRINT_CODE is set)
+  --trace           Print the stack and each instruction as it runs (also on
+                    when DEBUG_TRACE_EXECUTION is set)
+  --trace-only <names>
+                    Only trace the comma-separated functions (`script` is the
+                    top level)
+  --trace-skip-natives
+                    Don't trace code that natives call back into
+  --trace-compact   Trace one line per instruction
+  --trace-stack <n> Show at most n stack slots in compact traces (default 4)
+                    The --trace-* options imply --trace
+  -h, --help        Print this message
+  -V, --version     Print version and build information";
+
+/// Optional cargo features compiled into this build.
+const ENABLED_FEATURES: &[&str] = &[
+    #[cfg(feature = "boxed-constants")]
+    "boxed-constants",
+];
+
+#[derive(PartialEq, Debug)]
+pub enum Command {
+    Run { path: String },
+    Tokens { path: String },
+    Compile { path: String, output: String },
+    Disassemble { path: String },
+    Repl,
+    ExplainPrecedence,
+    GenTests { dir: String },
+    Help,
+    Version,
+}
+
+/// Global flags that apply to every command.
+#[derive(Default, PartialEq, Debug)]
+pub struct Options {
+    pub prelude: Option<String>,
+    pub time: bool,
+    pub buffered: bool,
+    pub trace: TraceOptions,
+    pub compiler: CompilerOptions,
+}
+
+impl Options {
+    /// Turns on the debugging output the `DEBUG_TRACE_EXECUTION` and
+    /// `DEBUG_PRINT_CODE` environment variables ask for. Flags can only turn
+    /// it on too, so it doesn't matter which comes first.
+    pub fn apply_env(&mut self, trace: bool, print_code: bool) {
+        self.trace.enabled |= trace;
+        self.compiler.dump_bytecode |= print_code;
+    }
+}
+
+#[derive(PartialEq, Debug)]
+pub struct Cli {
+    pub command: Command,
+    pub options: Options,
+}
+
+#[derive(PartialEq, Debug)]
+pub enum CliError {
+    MissingValue { flag: String },
+    InvalidValue { flag: String, value: String },
+    UnknownFlag { flag: String },
+    MissingPath { command: String },
+    UnexpectedArgument { arg: String },
+}
+
+impl fmt::Display for CliError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CliError::MissingValue { flag } => write!(f, "Missing value for {}", flag),
+            CliError::InvalidValue { flag, value } => {
+                write!(f, "Invalid value for {}: {}", flag, value)
+            }
+            CliError::UnknownFlag { flag } => write!(f, "Unknown option: {}", flag),
+            CliError::MissingPath { command } => write!(f, "Missing path for {}", command),
+            CliError::UnexpectedArgument { arg } => write!(f, "Unexpected argument: {}", arg),
+        }
+    }
+}
+
+/// Parses command-line arguments, excluding the program name. `prelude` is
+/// the default prelude path, which `--prelude` overrides.
+pub fn parse<I>(args: I, prelude: Option<String>) -> Result<Cli, CliError>
+where
+    I: IntoIterator<Item = String>,
+{
+    let mut args = args.into_iter();
+    let mut options = Options {
+        prelude,
+        ..Default::default()
+    };
+    let mut positional: Vec<String> = Vec::new();
+    let mut output: Option<String> = None;
+
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--prelude" => match args.next() {
+                Some(path) => options.prelude = Some(path),
+                None => return Err(CliError::MissingValue { flag: arg }),
+            },
+            "-o" | "--output" => match args.next() {
+                Some(path) => output = Some(path),
+                None => return Err(CliError::MissingValue { flag: arg }),
+            },
+            "--time" => options.time = true,
+            "--buffered" => options.buffered = true,
+            "--dump-bytecode" => options.compiler.dump_bytecode = true,
+            "--strict" => options.compiler.strict = true,
+            "--error-format" => match args.next().as_deref() {
+                Some("text") => options.compiler.error_format = ErrorFormat::Text,
+                Some("json") => options.compiler.error_format = ErrorFormat::Json,
+                Some(value) => {
+                    return Err(CliError::InvalidValue {
+                        flag: arg,
+                        value: value.to_string(),
+                    })
+                }
+                None => return Err(CliError::MissingValue { flag: arg }),
+            },
+            "--trace" => options.trace.enabled = true,
+            "--trace-only" => match args.next() {
+                Some(names) => {
+                    options.trace.enabled = true;
+                    options.trace.only = names.split(',').map(str::to_string).collect();
+                }
+                None => return Err(CliError::MissingValue { flag: arg }),
+            },
+            "--trace-skip-natives" => {
+                options.trace.enabled = true;
+                options.trace.skip_natives = true;
+            }
+            "--trace-compact" => {
+                options.trace.enabled = true;
+                options.trace.compact = true;
+            }
+            "--trace-stack" => match args.next() {
+                Some(value) => match value.parse() {
+                    Ok(count) => {
+                        options.trace.enabled = true;
+                        options.trace.stack_preview = count;
+                    }
+                    Err(_) => return Err(CliError::InvalidValue { flag: arg, value }),
+                },
+                None => return Err(CliError::MissingValue { flag: arg }),
+            },
+            "-h" | "--help" => {
+                return Ok(Cli {
+                    command: Command::Help,
+                    options,
+                })
+            }
+            "-V" | "--version" => {
+                return Ok(Cli {
+                    command: Command::Version,
+                    options,
+                })
+            }
+            _ if arg.starts_with('-') => return Err(CliError::UnknownFlag { flag: arg }),
+            _ => positional.push(arg),
+        }
+    }
+
+    let mut positional = positional.into_iter();
+    let command = match positional.next().as_deref() {
+        None | Some("repl") => Command::Repl,
+        Some("explain-precedence") => Command::ExplainPrecedence,
+        Some(name @ ("run" | "tokens" | "compile" | "disassemble" | "gen-tests")) => {
+            let path = positional.next().ok_or_else(|| CliError::MissingPath {
+                command: name.to_string(),
+            })?;
+
+            match name {
+                "run" => Command::Run { path },
+                "tokens" => Command::Tokens { path },
+                "disassemble" => Command::Disassemble { path },
+                "compile" => Command::Compile {
+                    output: output.take().unwrap_or_else(|| compiled_path(&path)),
+                    path,
+                },
+                _ => Command::GenTests { dir: path },
+            }
+        }
+        Some(path) => Command::Run {
+            path: path.to_string(),
+        },
+    };
+
+    if let Some(arg) = positional.next() {
+        return Err(CliError::UnexpectedArgument { arg });
+    }
+    // Only `compile` writes a file.
+    if output.is_some() {
+        return Err(CliError::UnexpectedArgument {
+            arg: "--output".to_string(),
+        });
+    }
+
+    Ok(Cli { command, options })
+}
+
+/// Where `compile` saves a script by default: next to it, with the
+/// extension swapped for `.loxc`.
+fn compiled_path(path: &str) -> String {
+    Path::new(path)
+        .with_extension("loxc")
+        .to_string_lossy()
+        .into_owned()
+}
+
+pub fn version() -> String {
+    let features = if ENABLED_FEATURES.is_empty() {
+        "none".to_string()
+    } else {
+        ENABLED_FEATURES.join(", ")
+    };
+
+    format!(
+        "rustlox {} ({})\nbytecode format: {}\nfeatures: {}",
+        env!("CARGO_PKG_VERSION"),
+        env!("RUSTLOX_GIT_HASH"),
+        BYTECODE_VERSION,
+        features
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(list: &[&str]) -> Vec<String> {
+        list.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    #[test]
+    fn parse_defaults_test() {
+        assert_eq!(
+            parse(args(&[]), None),
+            Ok(Cli {
+                command: Command::Repl,
+                options: Options::default()
+            })
+        );
+
+        assert_eq!(
+            parse(args(&["script.lox"]), None),
+            Ok(Cli {
+                command: Command::Run {
+                    path: "script.lox".to_string()
+                },
+                options: Options::default()
+            })
+        );
+    }
+
+    #[test]
+    fn parse_subcommands_test() {
+        assert_eq!(
+            parse(args(&["tokens", "script.lox"]), None)
+                .unwrap()
+                .command,
+            Command::Tokens {
+                path: "script.lox".to_string()
+            }
+        );
+        assert_eq!(
+            parse(args(&["run", "script.lox"]), None).unwrap().command,
+            Command::Run {
+                path: "script.lox".to_string()
+            }
+        );
+        assert_eq!(parse(args(&["repl"]), None).unwrap().command, Command::Repl);
+        assert_eq!(
+            parse(args(&["disassemble", "script.lox"]), None)
+                .unwrap()
+                .command,
+            Command::Disassemble {
+                path: "script.lox".to_string()
+            }
+        );
+        assert_eq!(
+            parse(args(&["compile", "dir/script.lox"]), None)
+                .unwrap()
+                .command,
+            Command::Compile {
+                path: "dir/script.lox".to_string(),
+                output: "dir/script.loxc".to_string()
+            }
+        );
+        assert_eq!(
+            parse(args(&["compile", "script.lox", "-o", "out.bin"]), None)
+                .unwrap()
+                .command,
+            Command::Compile {
+                path: "script.lox".to_string(),
+                output: "out.bin".to_string()
+            }
+        );
+        assert_eq!(
+            parse(args(&["explain-precedence"]), None).unwrap().command,
+            Command::ExplainPrecedence
+        );
+        assert_eq!(
+            parse(args(&["gen-tests", "fixtures"]), None)
+                .unwrap()
+                .command,
+            Command::GenTests {
+                dir: "fixtures".to_string()
+            }
+        );
+    }
+
+    #[test]
+    fn parse_prelude_test() {
+        let cli = parse(
+            args(&["--prelude", "a.lox", "b.lox"]),
+            Some("c.lox".to_string()),
+        );
+        assert_eq!(cli.unwrap().options.prelude, Some("a.lox".to_string()));
+
+        let cli = parse(args(&["b.lox"]), Some("c.lox".to_string()));
+        assert_eq!(cli.unwrap().options.prelude, Some("c.lox".to_string()));
+    }
+
+    #[test]
+    fn parse_time_test() {
+        assert!(
+            parse(args(&["--time", "a.lox"]), None)
+                .unwrap()
+                .options
+                .time
+        );
+        assert!(!parse(args(&["a.lox"]), None).unwrap().options.time);
+    }
+
+    #[test]
+    fn parse_buffered_test() {
+        assert!(
+            parse(args(&["--buffered", "a.lox"]), None)
+                .unwrap()
+                .options
+                .buffered
+        );
+        assert!(!parse(args(&["a.lox"]), None).unwrap().options.buffered);
+    }
+
+    #[test]
+    fn parse_trace_test() {
+        let cli = parse(
+            args(&[
+                "--trace-only",
+                "fib,script",
+                "--trace-skip-natives",
+                "--trace-compact",
+                "--trace-stack",
+                "2",
+                "a.lox",
+            ]),
+            None,
+        );
+
+        assert_eq!(
+            cli.unwrap().options.trace,
+            TraceOptions {
+                enabled: true,
+                only: vec!["fib".to_string(), "script".to_string()],
+                skip_natives: true,
+                compact: true,
+                stack_preview: 2,
+            }
+        );
+        assert_eq!(
+            parse(args(&["a.lox"]), None).unwrap().options.trace,
+            TraceOptions::default()
+        );
+    }
+
+    #[test]
+    fn parse_debug_output_test() {
+        let options = parse(args(&["--trace", "--dump-bytecode", "a.lox"]), None)
+            .unwrap()
+            .options;
+        assert!(options.trace.enabled);
+        assert!(options.compiler.dump_bytecode);
+        assert!(!options.compiler.strict);
+
+        let options = parse(args(&["--strict", "a.lox"]), None).unwrap().options;
+        assert!(options.compiler.strict);
+
+        let mut options = parse(args(&["a.lox"]), None).unwrap().options;
+        assert!(!options.trace.enabled);
+        assert!(!options.compiler.dump_bytecode);
+
+        options.apply_env(true, false);
+        assert!(options.trace.enabled);
+        assert!(!options.compiler.dump_bytecode);
+        options.apply_env(false, true);
+        assert!(options.trace.enabled);
+        assert!(options.compiler.dump_bytecode);
+    }
+
+    #[test]
+    fn parse_error_format_test() {
+        let options = parse(args(&["a.lox"]), None).unwrap().options;
+        assert_eq!(options.compiler.error_format, ErrorFormat::Text);
+
+        let options = parse(args(&["--error-format", "json", "compile", "a.lox"]), None)
+            .unwrap()
+            .options;
+        assert_eq!(options.compiler.error_format, ErrorFormat::Json);
+
+        assert_eq!(
+            parse(args(&["--error-format", "xml", "a.lox"]), None),
+            Err(CliError::InvalidValue {
+                flag: "--error-format".to_string(),
+                value: "xml".to_string()
+            })
+        );
+        assert_eq!(
+            parse(args(&["--error-format"]), None),
+            Err(CliError::MissingValue {
+                flag: "--error-format".to_string()
+            })
+        );
+    }
+
+    #[test]
+    fn parse_version_test() {
+        assert_eq!(
+            parse(args(&["--version"]), None).unwrap().command,
+            Command::Version
+        );
+        assert!(version().starts_with(&format!("rustlox {}", env!("CARGO_PKG_VERSION"))));
+    }
+
+    #[test]
+    fn parse_errors_test() {
+        assert_eq!(
+            parse(args(&["--prelude"]), None),
+            Err(CliError::MissingValue {
+                flag: "--prelude".to_string()
+            })
+        );
+        assert_eq!(
+            parse(args(&["--trace-stack", "lots"]), None),
+            Err(CliError::InvalidValue {
+                flag: "--trace-stack".to_string(),
+                value: "lots".to_string()
+            })
+        );
+        assert_eq!(
+            parse(args(&["--fast"]), None),
+            Err(CliError::UnknownFlag {
+                flag: "--fast".to_string()
+            })
+        );
+        assert_eq!(
+            parse(args(&["tokens"]), None),
+            Err(CliError::MissingPath {
+                command: "tokens".to_string()
+            })
+        );
+        assert_eq!(
+            parse(args(&["-o", "out.loxc", "a.lox"]), None),
+            Err(CliError::UnexpectedArgument {
+                arg: "--output".to_string()
+            })
+        );
+        assert_eq!(
+            parse(args(&["a.lox", "b.lox"]), None),
+            Err(CliError::UnexpectedArgument {
+                arg: "b.lox".to_string()
+            })
+        );
+    }
+}
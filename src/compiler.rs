@@ -1,40 +1,1883 @@
-use crate::scanner::{ScanError, Scanner, TokenType};
-use std::io::Write;
-
-pub fn compile<W: Write>(source: String, writer: &mut W) {
-    let mut scanner = Scanner::new(&source);
-    let mut line: usize = usize::MAX;
-
-    loop {
-        match scanner.scan_token() {
-            Ok(token) => {
-                if token.line != line {
-                    write!(writer, "{:4} ", token.line).unwrap();
-                    line = token.line;
-                } else {
-                    write!(writer, "   | ").unwrap();
+use crate::chunk::{Chunk, OpCode};
+use crate::debug::disassemble_chunk;
+use crate::diagnostics::{Diagnostic, Diagnostics, ErrorFormat, Location, Severity};
+use crate::interner::Interner;
+use crate::scanner::{ScanError, Scanner, Token, TokenType};
+use crate::symbols::{Symbol, SymbolTable};
+use crate::value::{ObjFunction, Value};
+use num_enum::TryFromPrimitive;
+use std::io::{self, Write};
+use std::rc::Rc;
+
+const UINT8_COUNT: usize = u8::MAX as usize + 1;
+
+#[repr(u8)]
+#[derive(Copy, Clone, PartialEq, PartialOrd, TryFromPrimitive, Debug)]
+enum Precedence {
+    None = 0,
+    Assignment = 1, // =
+    Or = 2,         // or
+    And = 3,        // and
+    Equality = 4,   // == !=
+    Comparison = 5, // < > <= >=
+    Term = 6,       // + -
+    Factor = 7,     // * /
+    Unary = 8,      // ! -
+    Call = 9,       // . ()
+    Primary = 10,
+}
+
+impl Precedence {
+    fn next(self) -> Self {
+        Precedence::try_from(self as u8 + 1).unwrap_or(Precedence::Primary)
+    }
+}
+
+type ParseFn<'a> = fn(&mut Compiler<'a>, bool);
+
+struct ParseRule<'a> {
+    prefix: Option<ParseFn<'a>>,
+    infix: Option<ParseFn<'a>>,
+    precedence: Precedence,
+}
+
+impl<'a> ParseRule<'a> {
+    fn new(
+        prefix: Option<ParseFn<'a>>,
+        infix: Option<ParseFn<'a>>,
+        precedence: Precedence,
+    ) -> Self {
+        ParseRule {
+            prefix,
+            infix,
+            precedence,
+        }
+    }
+}
+
+struct Parser {
+    current: Token,
+    previous: Token,
+    had_error: bool,
+    panic_mode: bool,
+}
+
+struct Local {
+    name: Token,
+    symbol: Symbol,
+    /// Scope depth of the block that declared the variable, or `None` while
+    /// its initializer is still being compiled.
+    depth: Option<usize>,
+    /// Declared without an initializer and, on at least one path through
+    /// the code compiled so far, not assigned since.
+    unassigned: bool,
+    /// Already reported as read while unassigned.
+    warned: bool,
+}
+
+#[derive(Copy, Clone, PartialEq)]
+enum FunctionType {
+    Function,
+    Script,
+}
+
+/// Compilation state for one function. Function declarations nest, so the
+/// compiler keeps a stack of these with the innermost function on top.
+struct FunctionState {
+    function: ObjFunction,
+    function_type: FunctionType,
+    locals: Vec<Local>,
+    scope_depth: usize,
+}
+
+impl FunctionState {
+    fn new(function_type: FunctionType, function: ObjFunction) -> Self {
+        // Slot zero holds the function being called. Its empty name can't
+        // clash with any identifier.
+        let callee = Local {
+            name: Token::new(TokenType::Identifier, 0, 0, 0),
+            symbol: Symbol::EMPTY,
+            depth: Some(0),
+            unassigned: false,
+            warned: false,
+        };
+
+        FunctionState {
+            function,
+            function_type,
+            locals: vec![callee],
+            scope_depth: 0,
+        }
+    }
+}
+
+struct Compiler<'a> {
+    source: &'a str,
+    scanner: Scanner,
+    parser: Parser,
+    strings: &'a mut Interner,
+    writer: &'a mut dyn Write,
+    /// Where diagnostics are printed as they're found.
+    errors: &'a mut dyn Write,
+    states: Vec<FunctionState>,
+    symbols: SymbolTable,
+    diagnostics: Diagnostics,
+    /// Whether the expression just compiled was a `<`, `>`, `<=` or `>=`, so
+    /// `binary` can spot chains like `a < b < c`.
+    last_was_comparison: bool,
+    options: CompilerOptions,
+}
+
+/// Prints each precedence level, lowest first, with the operators the rule
+/// table currently binds at that level.
+pub fn explain_precedence<W: Write + ?Sized>(writer: &mut W) {
+    let token_types: Vec<TokenType> = (0..=u8::MAX)
+        .filter_map(|byte| TokenType::try_from(byte).ok())
+        .collect();
+
+    writeln!(
+        writer,
+        "{:<6} {:<11} {:<14} Operators",
+        "Level", "Name", "Associativity"
+    )
+    .unwrap();
+
+    for level in (Precedence::Assignment as u8)..=(Precedence::Primary as u8) {
+        let precedence = Precedence::try_from(level).unwrap();
+
+        let (associativity, operators): (&str, Vec<&str>) = match precedence {
+            // Assignment is parsed by `named_variable`, not the rule table.
+            Precedence::Assignment => ("right", vec!["="]),
+            // Prefix operators all parse their operand at this level. Groups
+            // and object literals are primaries, not operators.
+            Precedence::Unary => (
+                "prefix",
+                token_types
+                    .iter()
+                    .filter(|&&token_type| {
+                        token_type != TokenType::LeftParen
+                            && token_type != TokenType::LeftBrace
+                            && Compiler::get_rule(token_type).prefix.is_some()
+                    })
+                    .filter_map(|token_type| token_type.text())
+                    .filter(|text| !text.chars().all(char::is_alphabetic))
+                    .collect(),
+            ),
+            // Infix operators parse their right operand one level higher.
+            _ => (
+                "left",
+                token_types
+                    .iter()
+                    .filter(|&&token_type| {
+                        let rule = Compiler::get_rule(token_type);
+                        rule.infix.is_some() && rule.precedence == precedence
+                    })
+                    .filter_map(|token_type| token_type.text())
+                    .collect(),
+            ),
+        };
+
+        let row = format!(
+            "{:<6} {:<11} {:<14} {}",
+            level,
+            format!("{:?}", precedence),
+            if operators.is_empty() {
+                ""
+            } else {
+                associativity
+            },
+            operators.join(" ")
+        );
+        writeln!(writer, "{}", row.trim_end()).unwrap();
+    }
+}
+
+/// Settings that change what the compiler prints, not the code it emits.
+#[derive(Copy, Clone, Default, PartialEq, Debug)]
+pub struct CompilerOptions {
+    /// Disassemble each function to the writer as it's finished, unless the
+    /// source had errors.
+    pub dump_bytecode: bool,
+    /// Report reading a local that may still be unassigned as an error
+    /// instead of a warning.
+    pub strict: bool,
+    /// How to write each error and warning to the errors writer.
+    pub error_format: ErrorFormat,
+}
+
+/// What a compile without errors produces.
+pub struct Compiled {
+    /// The function for the top-level script.
+    pub function: ObjFunction,
+    /// Any warnings found along the way.
+    pub diagnostics: Diagnostics,
+}
+
+/// Compiles `source` into the function for its top-level script, reporting
+/// errors to stderr as they're found. String constants are interned in
+/// `strings`. If the source had any compile errors, returns all of them.
+pub fn compile<W: Write>(
+    source: &str,
+    strings: &mut Interner,
+    writer: &mut W,
+) -> Result<Compiled, Diagnostics> {
+    let options = CompilerOptions::default();
+    compile_with_options(source, strings, writer, &mut io::stderr(), options)
+}
+
+/// Like `compile`, but reports errors and warnings to `errors` instead of
+/// stderr.
+pub fn compile_with_options<W: Write>(
+    source: &str,
+    strings: &mut Interner,
+    writer: &mut W,
+    errors: &mut dyn Write,
+    options: CompilerOptions,
+) -> Result<Compiled, Diagnostics> {
+    run_compiler(source, strings, writer, errors, options).0
+}
+
+/// Like `compile_with_options`, but also returns every identifier in
+/// `source` and where it appears, even if the source had compile errors.
+/// Meant for tools; the interpreter itself doesn't need the symbols.
+pub fn compile_with_symbols<W: Write>(
+    source: &str,
+    strings: &mut Interner,
+    writer: &mut W,
+    errors: &mut dyn Write,
+    options: CompilerOptions,
+) -> (Result<Compiled, Diagnostics>, SymbolTable) {
+    run_compiler(source, strings, writer, errors, options)
+}
+
+fn run_compiler<W: Write>(
+    source: &str,
+    strings: &mut Interner,
+    writer: &mut W,
+    errors: &mut dyn Write,
+    options: CompilerOptions,
+) -> (Result<Compiled, Diagnostics>, SymbolTable) {
+    let mut compiler = Compiler::new(source, strings, writer, errors, options);
+
+    compiler.advance();
+
+    while !compiler.matches(TokenType::Eof) {
+        compiler.declaration();
+    }
+
+    let function = compiler.end_compiler();
+
+    let diagnostics = compiler.diagnostics().clone();
+    let result = if compiler.parser.had_error {
+        Err(diagnostics)
+    } else {
+        Ok(Compiled {
+            function,
+            diagnostics,
+        })
+    };
+
+    (result, compiler.symbols)
+}
+
+impl<'a> Compiler<'a> {
+    fn new(
+        source: &'a str,
+        strings: &'a mut Interner,
+        writer: &'a mut dyn Write,
+        errors: &'a mut dyn Write,
+        options: CompilerOptions,
+    ) -> Self {
+        let placeholder = Token::new(TokenType::Eof, 0, 0, 1);
+
+        Compiler {
+            source,
+            scanner: Scanner::new(source),
+            parser: Parser {
+                current: placeholder,
+                previous: placeholder,
+                had_error: false,
+                panic_mode: false,
+            },
+            strings,
+            writer,
+            errors,
+            states: vec![FunctionState::new(
+                FunctionType::Script,
+                ObjFunction::new(None),
+            )],
+            symbols: SymbolTable::new(),
+            diagnostics: Diagnostics::default(),
+            last_was_comparison: false,
+            options,
+        }
+    }
+
+    fn diagnostics(&self) -> &Diagnostics {
+        &self.diagnostics
+    }
+
+    fn advance(&mut self) {
+        self.parser.previous = self.parser.current;
+
+        loop {
+            match self.scanner.scan_token() {
+                Ok(token) => {
+                    if token.token_type == TokenType::Identifier {
+                        let symbol = self.symbols.intern(token.lexeme(self.source));
+                        self.symbols.record(symbol, token.span());
+                    }
+                    self.parser.current = token;
+                    break;
                 }
+                Err(err) => self.scan_error(err),
+            }
+        }
+    }
 
-                writeln!(
-                    writer,
-                    "{:2} '{}'",
-                    token.token_type as i32,
-                    &source[token.start..token.start + token.length]
-                )
-                .unwrap();
+    fn consume(&mut self, token_type: TokenType, message: &str) {
+        if self.parser.current.token_type == token_type {
+            self.advance();
+            return;
+        }
+
+        self.error_at_current(message);
+    }
+
+    fn check(&self, token_type: TokenType) -> bool {
+        self.parser.current.token_type == token_type
+    }
+
+    fn matches(&mut self, token_type: TokenType) -> bool {
+        if !self.check(token_type) {
+            return false;
+        }
+
+        self.advance();
+        true
+    }
+
+    fn state(&self) -> &FunctionState {
+        self.states.last().expect("No function being compiled")
+    }
+
+    fn state_mut(&mut self) -> &mut FunctionState {
+        self.states.last_mut().expect("No function being compiled")
+    }
+
+    fn current_chunk(&mut self) -> &mut Chunk {
+        &mut self.state_mut().function.chunk
+    }
+
+    fn emit_byte(&mut self, byte: u8) {
+        let line = self.parser.previous.line;
+        self.current_chunk().write(byte, line);
+    }
+
+    fn emit_bytes(&mut self, byte1: u8, byte2: u8) {
+        self.emit_byte(byte1);
+        self.emit_byte(byte2);
+    }
+
+    /// Emits a jump instruction with a placeholder offset and returns the
+    /// position of the offset so `patch_jump` can fill it in later.
+    fn emit_jump(&mut self, instruction: OpCode) -> usize {
+        self.emit_byte(instruction as u8);
+        self.emit_byte(0xff);
+        self.emit_byte(0xff);
+        self.current_chunk().len() - 2
+    }
+
+    fn emit_loop(&mut self, loop_start: usize) {
+        self.emit_byte(OpCode::Loop as u8);
+
+        // +2 to skip over the loop instruction's own operand.
+        let offset = self.current_chunk().len() - loop_start + 2;
+        let offset = match u16::try_from(offset) {
+            Ok(offset) => offset,
+            Err(_) => {
+                self.error("Loop body too large.");
+                0
+            }
+        };
+
+        let [high, low] = offset.to_be_bytes();
+        self.emit_bytes(high, low);
+    }
+
+    fn patch_jump(&mut self, offset: usize) {
+        // -2 to adjust for the bytecode for the jump offset itself.
+        let jump = self.current_chunk().len() - offset - 2;
+
+        let jump = match u16::try_from(jump) {
+            Ok(jump) => jump,
+            Err(_) => {
+                self.error("Too much code to jump over.");
+                return;
+            }
+        };
+
+        let [high, low] = jump.to_be_bytes();
+        self.current_chunk().patch(offset, high);
+        self.current_chunk().patch(offset + 1, low);
+    }
+
+    fn emit_return(&mut self) {
+        self.emit_byte(OpCode::Nil as u8);
+        self.emit_byte(OpCode::Return as u8);
+    }
+
+    fn make_constant(&mut self, value: Value) -> u8 {
+        let constant = self.current_chunk().add_constant(value);
+        match u8::try_from(constant) {
+            Ok(constant) => constant,
+            Err(_) => {
+                self.error("Too many constants in one chunk.");
+                0
+            }
+        }
+    }
+
+    fn emit_constant(&mut self, value: Value) {
+        let constant = self.make_constant(value);
+        self.emit_bytes(OpCode::Constant as u8, constant);
+    }
+
+    /// Finishes the innermost function and returns it.
+    fn end_compiler(&mut self) -> ObjFunction {
+        self.emit_return();
+
+        if self.options.dump_bytecode && !self.parser.had_error {
+            let state = self.states.last().expect("No function being compiled");
+            disassemble_chunk(
+                &state.function.chunk,
+                &state.function.to_string(),
+                &mut self.writer,
+            );
+        }
+
+        self.states
+            .pop()
+            .expect("No function being compiled")
+            .function
+    }
+
+    fn begin_scope(&mut self) {
+        self.state_mut().scope_depth += 1;
+    }
+
+    fn end_scope(&mut self) {
+        self.state_mut().scope_depth -= 1;
+
+        while self.state().locals.last().is_some_and(|local| {
+            local
+                .depth
+                .is_some_and(|depth| depth > self.state().scope_depth)
+        }) {
+            self.emit_byte(OpCode::Pop as u8);
+            self.state_mut().locals.pop();
+        }
+    }
+
+    fn expression(&mut self) {
+        self.parse_precedence(Precedence::Assignment);
+    }
+
+    fn block(&mut self) {
+        while !self.check(TokenType::RightBrace) && !self.check(TokenType::Eof) {
+            self.declaration();
+        }
+
+        self.consume(TokenType::RightBrace, "Expect '}' after block.");
+    }
+
+    fn function(&mut self, function_type: FunctionType) {
+        let name = self
+            .strings
+            .intern(self.parser.previous.lexeme(self.source));
+        self.states.push(FunctionState::new(
+            function_type,
+            ObjFunction::new(Some(name)),
+        ));
+        self.begin_scope();
+
+        self.consume(TokenType::LeftParen, "Expect '(' after function name.");
+        if !self.check(TokenType::RightParen) {
+            loop {
+                self.state_mut().function.arity += 1;
+                if self.state().function.arity > 255 {
+                    self.error_at_current("Can't have more than 255 parameters.");
+                }
+
+                let constant = self.parse_variable("Expect parameter name.");
+                self.define_variable(constant);
+
+                if !self.matches(TokenType::Comma) {
+                    break;
+                }
+            }
+        }
+        self.consume(TokenType::RightParen, "Expect ')' after parameters.");
+        self.consume(TokenType::LeftBrace, "Expect '{' before function body.");
+        self.block();
+
+        let function = self.end_compiler();
+        self.emit_constant(Value::Function(Rc::new(function)));
+    }
+
+    fn fun_declaration(&mut self) {
+        let global = self.parse_variable("Expect function name.");
+        // A function can refer to itself, so it's usable as soon as it's named.
+        self.mark_initialized();
+        self.function(FunctionType::Function);
+        self.define_variable(global);
+    }
+
+    fn var_declaration(&mut self) {
+        let global = self.parse_variable("Expect variable name.");
+
+        if self.matches(TokenType::Equal) {
+            self.expression();
+        } else {
+            self.emit_byte(OpCode::Nil as u8);
+
+            if self.state().scope_depth > 0 {
+                if let Some(local) = self.state_mut().locals.last_mut() {
+                    local.unassigned = true;
+                }
+            }
+        }
+
+        self.consume(
+            TokenType::Semicolon,
+            "Expect ';' after variable declaration.",
+        );
+
+        self.define_variable(global);
+    }
+
+    fn expression_statement(&mut self) {
+        self.expression();
+        self.consume(TokenType::Semicolon, "Expect ';' after expression.");
+        self.emit_byte(OpCode::Pop as u8);
+    }
+
+    fn for_statement(&mut self) {
+        self.begin_scope();
+        self.consume(TokenType::LeftParen, "Expect '(' after 'for'.");
+        if self.matches(TokenType::Semicolon) {
+            // No initializer.
+        } else if self.matches(TokenType::Var) {
+            self.var_declaration();
+        } else {
+            self.expression_statement();
+        }
+
+        let mut loop_start = self.current_chunk().len();
+        let mut exit_jump = None;
+        if !self.matches(TokenType::Semicolon) {
+            self.expression();
+            self.consume(TokenType::Semicolon, "Expect ';' after loop condition.");
+
+            // Jump out of the loop if the condition is false.
+            exit_jump = Some(self.emit_jump(OpCode::JumpIfFalse));
+            self.emit_byte(OpCode::Pop as u8);
+        }
+
+        // The body and increment may never run.
+        let before_body = self.unassigned_locals();
+
+        if !self.matches(TokenType::RightParen) {
+            let body_jump = self.emit_jump(OpCode::Jump);
+            let increment_start = self.current_chunk().len();
+            self.expression();
+            self.emit_byte(OpCode::Pop as u8);
+            self.consume(TokenType::RightParen, "Expect ')' after for clauses.");
+
+            self.emit_loop(loop_start);
+            loop_start = increment_start;
+            self.patch_jump(body_jump);
+
+            // The increment runs after the body, not before it.
+            self.restore_unassigned(&before_body);
+        }
+
+        self.statement();
+        self.emit_loop(loop_start);
+        self.merge_unassigned(&before_body);
+
+        if let Some(exit_jump) = exit_jump {
+            self.patch_jump(exit_jump);
+            self.emit_byte(OpCode::Pop as u8);
+        }
+
+        self.end_scope();
+    }
+
+    fn if_statement(&mut self) {
+        self.consume(TokenType::LeftParen, "Expect '(' after 'if'.");
+        self.expression();
+        self.consume(TokenType::RightParen, "Expect ')' after condition.");
+
+        let then_jump = self.emit_jump(OpCode::JumpIfFalse);
+        self.emit_byte(OpCode::Pop as u8);
+        let before_then = self.unassigned_locals();
+        self.statement();
+        let after_then = self.unassigned_locals();
+
+        let else_jump = self.emit_jump(OpCode::Jump);
+
+        self.patch_jump(then_jump);
+        self.emit_byte(OpCode::Pop as u8);
+
+        // The else branch, even an empty one, starts from the condition.
+        self.restore_unassigned(&before_then);
+        if self.matches(TokenType::Else) {
+            self.statement();
+        }
+        self.merge_unassigned(&after_then);
+        self.patch_jump(else_jump);
+    }
+
+    fn return_statement(&mut self) {
+        if self.state().function_type == FunctionType::Script {
+            self.error("Can't return from top-level code.");
+        }
+
+        if self.matches(TokenType::Semicolon) {
+            self.emit_return();
+        } else {
+            self.expression();
+            self.consume(TokenType::Semicolon, "Expect ';' after return value.");
+            self.emit_byte(OpCode::Return as u8);
+        }
+    }
+
+    fn while_statement(&mut self) {
+        let loop_start = self.current_chunk().len();
+        self.consume(TokenType::LeftParen, "Expect '(' after 'while'.");
+        self.expression();
+        self.consume(TokenType::RightParen, "Expect ')' after condition.");
+
+        let exit_jump = self.emit_jump(OpCode::JumpIfFalse);
+        self.emit_byte(OpCode::Pop as u8);
+        // The body may never run.
+        let before_body = self.unassigned_locals();
+        self.statement();
+        self.emit_loop(loop_start);
+        self.merge_unassigned(&before_body);
+
+        self.patch_jump(exit_jump);
+        self.emit_byte(OpCode::Pop as u8);
+    }
+
+    fn print_statement(&mut self) {
+        self.expression();
+        self.consume(TokenType::Semicolon, "Expect ';' after value.");
+        self.emit_byte(OpCode::Print as u8);
+    }
+
+    fn declaration(&mut self) {
+        if self.matches(TokenType::Fun) {
+            self.fun_declaration();
+        } else if self.matches(TokenType::Var) {
+            self.var_declaration();
+        } else {
+            self.statement();
+        }
+
+        if self.parser.panic_mode {
+            self.synchronize();
+        }
+    }
+
+    fn synchronize(&mut self) {
+        self.parser.panic_mode = false;
+
+        while self.parser.current.token_type != TokenType::Eof {
+            if self.parser.previous.token_type == TokenType::Semicolon {
+                return;
+            }
+
+            match self.parser.current.token_type {
+                TokenType::Class
+                | TokenType::Fun
+                | TokenType::Var
+                | TokenType::For
+                | TokenType::If
+                | TokenType::While
+                | TokenType::Print
+                | TokenType::Return => return,
+                _ => {}
+            }
+
+            self.advance();
+        }
+    }
+
+    fn statement(&mut self) {
+        if self.matches(TokenType::Print) {
+            self.print_statement();
+        } else if self.matches(TokenType::For) {
+            self.for_statement();
+        } else if self.matches(TokenType::If) {
+            self.if_statement();
+        } else if self.matches(TokenType::Return) {
+            self.return_statement();
+        } else if self.matches(TokenType::While) {
+            self.while_statement();
+        } else if self.matches(TokenType::LeftBrace) {
+            self.begin_scope();
+            self.block();
+            self.end_scope();
+        } else {
+            self.expression_statement();
+        }
+    }
+
+    fn number(&mut self, _can_assign: bool) {
+        let lexeme = self.parser.previous.lexeme(self.source);
+        // `str::parse` always expects a '.' decimal point, whatever the locale.
+        let value: f64 = lexeme.parse().expect("Scanner produced an invalid number");
+        self.emit_constant(Value::Number(value));
+    }
+
+    fn string(&mut self, _can_assign: bool) {
+        let lexeme = self.parser.previous.lexeme(self.source);
+        let chars = &lexeme[1..lexeme.len() - 1];
+        let string = self.strings.intern(chars);
+        self.emit_constant(Value::String(string));
+    }
+
+    fn named_variable(&mut self, name: Token, can_assign: bool) {
+        let (get_op, set_op, arg) = match self.resolve_local(name) {
+            Some(slot) => (OpCode::GetLocal, OpCode::SetLocal, slot),
+            None => (
+                OpCode::GetGlobal,
+                OpCode::SetGlobal,
+                self.identifier_constant(name),
+            ),
+        };
+
+        if can_assign && self.matches(TokenType::Equal) {
+            self.expression();
+            self.emit_bytes(set_op as u8, arg);
+
+            if matches!(get_op, OpCode::GetLocal) {
+                self.state_mut().locals[arg as usize].unassigned = false;
+            }
+        } else {
+            self.emit_bytes(get_op as u8, arg);
+
+            if matches!(get_op, OpCode::GetLocal) {
+                self.check_assigned(arg);
+            }
+        }
+    }
+
+    /// Warns when a local is read while some path to the read hasn't
+    /// assigned it, so it may still be nil. Loops run their first iteration
+    /// in source order, so that's the only one checked. Under strict mode
+    /// this is an error instead.
+    fn check_assigned(&mut self, slot: u8) {
+        let local = &mut self.state_mut().locals[slot as usize];
+        // Only report each variable once.
+        if !local.unassigned || local.warned {
+            return;
+        }
+        local.warned = true;
+
+        let name = local.name;
+        let message = format!(
+            "Local variable '{}' may be read before it's assigned; it will be nil.",
+            name.lexeme(self.source)
+        );
+        if self.options.strict {
+            self.error(&message);
+        } else {
+            self.warning(&message);
+        }
+    }
+
+    /// Which of the current function's locals may still be unassigned, to
+    /// save before compiling code that might not run.
+    fn unassigned_locals(&self) -> Vec<bool> {
+        self.state()
+            .locals
+            .iter()
+            .map(|local| local.unassigned)
+            .collect()
+    }
+
+    /// Goes back to the flags `saved` before compiling another path from
+    /// the same point.
+    fn restore_unassigned(&mut self, saved: &[bool]) {
+        for (local, &unassigned) in self.state_mut().locals.iter_mut().zip(saved) {
+            local.unassigned = unassigned;
+        }
+    }
+
+    /// Joins the path just compiled with another that left the flags in
+    /// `other`. A local may be unassigned if it is on either path.
+    fn merge_unassigned(&mut self, other: &[bool]) {
+        for (local, &unassigned) in self.state_mut().locals.iter_mut().zip(other) {
+            local.unassigned |= unassigned;
+        }
+    }
+
+    fn variable(&mut self, can_assign: bool) {
+        self.named_variable(self.parser.previous, can_assign);
+    }
 
-                if token.token_type == TokenType::Eof {
+    fn grouping(&mut self, _can_assign: bool) {
+        self.expression();
+        self.consume(TokenType::RightParen, "Expect ')' after expression.");
+    }
+
+    fn unary(&mut self, _can_assign: bool) {
+        let operator_type = self.parser.previous.token_type;
+
+        self.parse_precedence(Precedence::Unary);
+
+        match operator_type {
+            TokenType::Bang => self.emit_byte(OpCode::Not as u8),
+            TokenType::Minus => self.emit_byte(OpCode::Negate as u8),
+            _ => unreachable!(),
+        }
+    }
+
+    fn binary(&mut self, _can_assign: bool) {
+        let operator_type = self.parser.previous.token_type;
+        let is_comparison = Self::get_rule(operator_type).precedence == Precedence::Comparison;
+
+        if is_comparison && self.last_was_comparison {
+            self.warning(
+                "Chained comparison compares the boolean result of the first comparison \
+                 with the next operand.",
+            );
+        }
+
+        let rule = Self::get_rule(operator_type);
+        self.parse_precedence(rule.precedence.next());
+
+        match operator_type {
+            TokenType::BangEqual => self.emit_bytes(OpCode::Equal as u8, OpCode::Not as u8),
+            TokenType::EqualEqual => self.emit_byte(OpCode::Equal as u8),
+            TokenType::Greater => self.emit_byte(OpCode::Greater as u8),
+            TokenType::GreaterEqual => self.emit_bytes(OpCode::Less as u8, OpCode::Not as u8),
+            TokenType::Less => self.emit_byte(OpCode::Less as u8),
+            TokenType::LessEqual => self.emit_bytes(OpCode::Greater as u8, OpCode::Not as u8),
+            TokenType::Plus => self.emit_byte(OpCode::Add as u8),
+            TokenType::Minus => self.emit_byte(OpCode::Subtract as u8),
+            TokenType::Star => self.emit_byte(OpCode::Multiply as u8),
+            TokenType::Slash => self.emit_byte(OpCode::Divide as u8),
+            _ => unreachable!(),
+        }
+
+        self.last_was_comparison = is_comparison;
+    }
+
+    fn call(&mut self, _can_assign: bool) {
+        let arg_count = self.argument_list();
+        self.emit_bytes(OpCode::Call as u8, arg_count);
+        // The arguments were separate expressions, whatever they contained.
+        self.last_was_comparison = false;
+    }
+
+    fn argument_list(&mut self) -> u8 {
+        let mut arg_count: u8 = 0;
+
+        if !self.check(TokenType::RightParen) {
+            loop {
+                self.expression();
+                if arg_count == 255 {
+                    self.error("Can't have more than 255 arguments.");
+                } else {
+                    arg_count += 1;
+                }
+
+                if !self.matches(TokenType::Comma) {
                     break;
                 }
             }
-            Err(err) => match err {
-                ScanError::UnexpectedChar { line } => {
-                    writeln!(writer, "Unexpected char on line: {}", line).unwrap();
+        }
+
+        self.consume(TokenType::RightParen, "Expect ')' after arguments.");
+        arg_count
+    }
+
+    /// An object literal, `{ x: 1, y: 2 }`. A `{` that starts a statement
+    /// is always a block, so literals only appear inside expressions.
+    fn object(&mut self, _can_assign: bool) {
+        let mut names: Vec<&'a str> = Vec::new();
+
+        if !self.check(TokenType::RightBrace) {
+            loop {
+                self.consume(TokenType::Identifier, "Expect field name.");
+                let name = self.parser.previous;
+                let lexeme = name.lexeme(self.source);
+                if names.contains(&lexeme) {
+                    self.error(&format!("Duplicate field '{}' in object literal.", lexeme));
                 }
-                ScanError::UnterminatedString { line } => {
-                    writeln!(writer, "Unterminated string on line: {}", line).unwrap();
+
+                let constant = self.identifier_constant(name);
+                self.emit_bytes(OpCode::Constant as u8, constant);
+                self.consume(TokenType::Colon, "Expect ':' after field name.");
+                self.expression();
+
+                if names.len() == 255 {
+                    self.error("Can't have more than 255 fields in an object literal.");
+                } else {
+                    names.push(lexeme);
                 }
-            },
+
+                // Allow a trailing comma.
+                if !self.matches(TokenType::Comma) || self.check(TokenType::RightBrace) {
+                    break;
+                }
+            }
+        }
+
+        self.consume(TokenType::RightBrace, "Expect '}' after object literal.");
+        self.emit_bytes(OpCode::Object as u8, names.len() as u8);
+    }
+
+    fn dot(&mut self, _can_assign: bool) {
+        self.consume(TokenType::Identifier, "Expect property name after '.'.");
+        let name = self.identifier_constant(self.parser.previous);
+        self.emit_bytes(OpCode::GetProperty as u8, name);
+    }
+
+    fn literal(&mut self, _can_assign: bool) {
+        match self.parser.previous.token_type {
+            TokenType::False => self.emit_byte(OpCode::False as u8),
+            TokenType::Nil => self.emit_byte(OpCode::Nil as u8),
+            TokenType::True => self.emit_byte(OpCode::True as u8),
+            _ => unreachable!(),
+        }
+    }
+
+    fn parse_precedence(&mut self, precedence: Precedence) {
+        self.advance();
+
+        let prefix_rule = match Self::get_rule(self.parser.previous.token_type).prefix {
+            Some(rule) => rule,
+            None => {
+                self.error("Expect expression.");
+                return;
+            }
+        };
+
+        let can_assign = precedence <= Precedence::Assignment;
+        prefix_rule(self, can_assign);
+        self.last_was_comparison = false;
+
+        while precedence <= Self::get_rule(self.parser.current.token_type).precedence {
+            self.advance();
+            if let Some(infix_rule) = Self::get_rule(self.parser.previous.token_type).infix {
+                infix_rule(self, can_assign);
+            }
+        }
+
+        if can_assign && self.matches(TokenType::Equal) {
+            self.error("Invalid assignment target.");
         }
     }
+
+    fn identifier_constant(&mut self, name: Token) -> u8 {
+        let string = self.strings.intern(name.lexeme(self.source));
+        self.make_constant(Value::String(string))
+    }
+
+    fn symbol(&mut self, name: Token) -> Symbol {
+        self.symbols.intern(name.lexeme(self.source))
+    }
+
+    fn resolve_local(&mut self, name: Token) -> Option<u8> {
+        let symbol = self.symbol(name);
+        let (slot, local) = self
+            .state()
+            .locals
+            .iter()
+            .enumerate()
+            .rev()
+            .find(|(_, local)| local.symbol == symbol)?;
+
+        if local.depth.is_none() {
+            self.error("Can't read local variable in its own initializer.");
+        }
+
+        Some(slot as u8)
+    }
+
+    fn add_local(&mut self, name: Token) {
+        if self.state().locals.len() == UINT8_COUNT {
+            self.error("Too many local variables in function.");
+            return;
+        }
+
+        let symbol = self.symbol(name);
+        self.state_mut().locals.push(Local {
+            name,
+            symbol,
+            depth: None,
+            unassigned: false,
+            warned: false,
+        });
+    }
+
+    fn declare_variable(&mut self) {
+        if self.state().scope_depth == 0 {
+            return;
+        }
+
+        let name = self.parser.previous;
+        let symbol = self.symbol(name);
+        let scope_depth = self.state().scope_depth;
+        let duplicate = self
+            .state()
+            .locals
+            .iter()
+            .rev()
+            .take_while(|local| local.depth.is_none_or(|depth| depth >= scope_depth))
+            .any(|local| local.symbol == symbol);
+
+        if duplicate {
+            self.error("Already a variable with this name in this scope.");
+        }
+
+        self.add_local(name);
+    }
+
+    fn parse_variable(&mut self, message: &str) -> u8 {
+        self.consume(TokenType::Identifier, message);
+
+        self.declare_variable();
+        if self.state().scope_depth > 0 {
+            return 0;
+        }
+
+        self.identifier_constant(self.parser.previous)
+    }
+
+    fn mark_initialized(&mut self) {
+        let state = self.state_mut();
+        if state.scope_depth == 0 {
+            return;
+        }
+
+        if let Some(local) = state.locals.last_mut() {
+            local.depth = Some(state.scope_depth);
+        }
+    }
+
+    fn define_variable(&mut self, global: u8) {
+        if self.state().scope_depth > 0 {
+            self.mark_initialized();
+            return;
+        }
+
+        self.emit_bytes(OpCode::DefineGlobal as u8, global);
+    }
+
+    fn get_rule(token_type: TokenType) -> ParseRule<'a> {
+        match token_type {
+            TokenType::LeftParen => {
+                ParseRule::new(Some(Self::grouping), Some(Self::call), Precedence::Call)
+            }
+            TokenType::LeftBrace => ParseRule::new(Some(Self::object), None, Precedence::None),
+            TokenType::Dot => ParseRule::new(None, Some(Self::dot), Precedence::Call),
+            TokenType::Minus => {
+                ParseRule::new(Some(Self::unary), Some(Self::binary), Precedence::Term)
+            }
+            TokenType::Plus => ParseRule::new(None, Some(Self::binary), Precedence::Term),
+            TokenType::Slash => ParseRule::new(None, Some(Self::binary), Precedence::Factor),
+            TokenType::Star => ParseRule::new(None, Some(Self::binary), Precedence::Factor),
+            TokenType::Bang => ParseRule::new(Some(Self::unary), None, Precedence::None),
+            TokenType::BangEqual => ParseRule::new(None, Some(Self::binary), Precedence::Equality),
+            TokenType::EqualEqual => ParseRule::new(None, Some(Self::binary), Precedence::Equality),
+            TokenType::Greater => ParseRule::new(None, Some(Self::binary), Precedence::Comparison),
+            TokenType::GreaterEqual => {
+                ParseRule::new(None, Some(Self::binary), Precedence::Comparison)
+            }
+            TokenType::Less => ParseRule::new(None, Some(Self::binary), Precedence::Comparison),
+            TokenType::LessEqual => {
+                ParseRule::new(None, Some(Self::binary), Precedence::Comparison)
+            }
+            TokenType::Identifier => ParseRule::new(Some(Self::variable), None, Precedence::None),
+            TokenType::String => ParseRule::new(Some(Self::string), None, Precedence::None),
+            TokenType::Number => ParseRule::new(Some(Self::number), None, Precedence::None),
+            TokenType::False => ParseRule::new(Some(Self::literal), None, Precedence::None),
+            TokenType::Nil => ParseRule::new(Some(Self::literal), None, Precedence::None),
+            TokenType::True => ParseRule::new(Some(Self::literal), None, Precedence::None),
+            _ => ParseRule::new(None, None, Precedence::None),
+        }
+    }
+
+    fn error_at_current(&mut self, message: &str) {
+        self.error_at(self.parser.current, message);
+    }
+
+    fn error(&mut self, message: &str) {
+        self.error_at(self.parser.previous, message);
+    }
+
+    fn error_at(&mut self, token: Token, message: &str) {
+        let location = match token.token_type {
+            TokenType::Eof => Location::AtEnd,
+            _ => Location::At(token.lexeme(self.source).to_string()),
+        };
+
+        self.report(token.line, location, message);
+    }
+
+    fn warning(&mut self, message: &str) {
+        if self.parser.panic_mode {
+            return;
+        }
+
+        let token = self.parser.previous;
+        let diagnostic = Diagnostic {
+            severity: Severity::Warning,
+            line: token.line,
+            location: Location::At(token.lexeme(self.source).to_string()),
+            message: message.to_string(),
+        };
+        self.emit(diagnostic);
+    }
+
+    fn scan_error(&mut self, err: ScanError) {
+        self.report(err.line(), Location::Unknown, &err.to_string());
+    }
+
+    fn report(&mut self, line: usize, location: Location, message: &str) {
+        if self.parser.panic_mode {
+            return;
+        }
+        self.parser.panic_mode = true;
+
+        let diagnostic = Diagnostic {
+            severity: Severity::Error,
+            line,
+            location,
+            message: message.to_string(),
+        };
+        self.emit(diagnostic);
+        self.parser.had_error = true;
+    }
+
+    fn emit(&mut self, diagnostic: Diagnostic) {
+        match self.options.error_format {
+            ErrorFormat::Text => writeln!(self.errors, "{}", diagnostic).unwrap(),
+            ErrorFormat::Json => writeln!(self.errors, "{}", diagnostic.to_json()).unwrap(),
+        }
+        self.diagnostics.push(diagnostic);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn compile_to_chunk(source: &str) -> Option<Chunk> {
+        let mut strings = Interner::new();
+        let mut output = Vec::new();
+
+        compile(source, &mut strings, &mut output)
+            .ok()
+            .map(|compiled| compiled.function.chunk)
+    }
+
+    fn compile_errors(source: &str) -> Diagnostics {
+        let mut strings = Interner::new();
+        let mut output = Vec::new();
+
+        compile(source, &mut strings, &mut output).err().unwrap()
+    }
+
+    #[test]
+    fn compile_number_test() {
+        let chunk = compile_to_chunk("1.2;").unwrap();
+
+        assert_eq!(
+            chunk.code(),
+            [
+                OpCode::Constant as u8,
+                0,
+                OpCode::Pop as u8,
+                OpCode::Nil as u8,
+                OpCode::Return as u8
+            ]
+        );
+        assert_eq!(chunk.constants.at(0), Value::Number(1.2));
+    }
+
+    #[test]
+    fn compile_precedence_test() {
+        let chunk = compile_to_chunk("1 + 2 * -3;").unwrap();
+
+        assert_eq!(
+            chunk.code(),
+            [
+                OpCode::Constant as u8,
+                0,
+                OpCode::Constant as u8,
+                1,
+                OpCode::Constant as u8,
+                2,
+                OpCode::Negate as u8,
+                OpCode::Multiply as u8,
+                OpCode::Add as u8,
+                OpCode::Pop as u8,
+                OpCode::Nil as u8,
+                OpCode::Return as u8
+            ]
+        );
+    }
+
+    #[test]
+    fn compile_grouping_test() {
+        let chunk = compile_to_chunk("(1 - 2) / 3;").unwrap();
+
+        assert_eq!(
+            chunk.code(),
+            [
+                OpCode::Constant as u8,
+                0,
+                OpCode::Constant as u8,
+                1,
+                OpCode::Subtract as u8,
+                OpCode::Constant as u8,
+                2,
+                OpCode::Divide as u8,
+                OpCode::Pop as u8,
+                OpCode::Nil as u8,
+                OpCode::Return as u8
+            ]
+        );
+    }
+
+    #[test]
+    fn compile_literal_test() {
+        let chunk = compile_to_chunk("!nil;").unwrap();
+        assert_eq!(
+            chunk.code(),
+            [
+                OpCode::Nil as u8,
+                OpCode::Not as u8,
+                OpCode::Pop as u8,
+                OpCode::Nil as u8,
+                OpCode::Return as u8
+            ]
+        );
+
+        let chunk = compile_to_chunk("true;").unwrap();
+        assert_eq!(
+            chunk.code(),
+            [
+                OpCode::True as u8,
+                OpCode::Pop as u8,
+                OpCode::Nil as u8,
+                OpCode::Return as u8
+            ]
+        );
+
+        let chunk = compile_to_chunk("false;").unwrap();
+        assert_eq!(
+            chunk.code(),
+            [
+                OpCode::False as u8,
+                OpCode::Pop as u8,
+                OpCode::Nil as u8,
+                OpCode::Return as u8
+            ]
+        );
+    }
+
+    #[test]
+    fn compile_comparison_test() {
+        let cases = [
+            ("1 == 2;", vec![OpCode::Equal]),
+            ("1 != 2;", vec![OpCode::Equal, OpCode::Not]),
+            ("1 > 2;", vec![OpCode::Greater]),
+            ("1 >= 2;", vec![OpCode::Less, OpCode::Not]),
+            ("1 < 2;", vec![OpCode::Less]),
+            ("1 <= 2;", vec![OpCode::Greater, OpCode::Not]),
+        ];
+
+        for (source, ops) in cases {
+            let chunk = compile_to_chunk(source).unwrap();
+
+            let mut expected = vec![OpCode::Constant as u8, 0, OpCode::Constant as u8, 1];
+            expected.extend(ops.iter().map(|op| *op as u8));
+            expected.push(OpCode::Pop as u8);
+            expected.push(OpCode::Nil as u8);
+            expected.push(OpCode::Return as u8);
+
+            assert_eq!(chunk.code(), expected);
+        }
+    }
+
+    #[test]
+    fn compile_string_test() {
+        let mut strings = Interner::new();
+        let mut output = Vec::new();
+
+        let chunk = compile("\"hello\" + \"hello\";", &mut strings, &mut output)
+            .ok()
+            .unwrap()
+            .function
+            .chunk;
+
+        assert_eq!(
+            chunk.code(),
+            [
+                OpCode::Constant as u8,
+                0,
+                OpCode::Constant as u8,
+                1,
+                OpCode::Add as u8,
+                OpCode::Pop as u8,
+                OpCode::Nil as u8,
+                OpCode::Return as u8
+            ]
+        );
+        assert_eq!(
+            chunk.constants.at(0),
+            Value::String(strings.intern("hello"))
+        );
+        assert_eq!(chunk.constants.at(0), chunk.constants.at(1));
+        assert_eq!(strings.len(), 1);
+    }
+
+    #[test]
+    fn compile_global_test() {
+        let chunk = compile_to_chunk("var a = 1; a = 2; print a;").unwrap();
+
+        assert_eq!(
+            chunk.code(),
+            [
+                OpCode::Constant as u8,
+                1,
+                OpCode::DefineGlobal as u8,
+                0,
+                OpCode::Constant as u8,
+                3,
+                OpCode::SetGlobal as u8,
+                2,
+                OpCode::Pop as u8,
+                OpCode::GetGlobal as u8,
+                4,
+                OpCode::Print as u8,
+                OpCode::Nil as u8,
+                OpCode::Return as u8
+            ]
+        );
+    }
+
+    #[test]
+    fn compile_local_test() {
+        let chunk = compile_to_chunk("{ var a = 1; { var b = a; b = 2; } print a; }").unwrap();
+
+        assert_eq!(
+            chunk.code(),
+            [
+                OpCode::Constant as u8,
+                0,
+                OpCode::GetLocal as u8,
+                1,
+                OpCode::Constant as u8,
+                1,
+                OpCode::SetLocal as u8,
+                2,
+                OpCode::Pop as u8,
+                OpCode::Pop as u8,
+                OpCode::GetLocal as u8,
+                1,
+                OpCode::Print as u8,
+                OpCode::Pop as u8,
+                OpCode::Nil as u8,
+                OpCode::Return as u8
+            ]
+        );
+    }
+
+    #[test]
+    fn compile_shadowing_test() {
+        assert!(compile_to_chunk("{ var a = 1; { var a = 2; } }").is_some());
+        assert!(compile_to_chunk("var a = 1; var a = 2;").is_some());
+        assert!(compile_to_chunk("{ var a = 1; var a = 2; }").is_none());
+    }
+
+    #[test]
+    fn compile_self_initializer_test() {
+        assert!(compile_to_chunk("{ var a = a; }").is_none());
+        assert!(compile_to_chunk("var a = a;").is_some());
+    }
+
+    #[test]
+    fn compile_too_many_locals_test() {
+        // Slot zero is reserved for the function being called.
+        let declarations: String = (1..UINT8_COUNT).map(|i| format!("var a{};", i)).collect();
+        assert!(compile_to_chunk(&format!("{{ {} }}", declarations)).is_some());
+
+        let declarations: String = (0..UINT8_COUNT).map(|i| format!("var a{};", i)).collect();
+        assert!(compile_to_chunk(&format!("{{ {} }}", declarations)).is_none());
+    }
+
+    #[test]
+    fn compile_error_test() {
+        assert!(compile_to_chunk("1 +;").is_none());
+        assert!(compile_to_chunk("(1;").is_none());
+        assert!(compile_to_chunk("1 2;").is_none());
+        assert!(compile_to_chunk("#").is_none());
+        assert!(compile_to_chunk("1").is_none());
+        assert!(compile_to_chunk("{ 1;").is_none());
+        assert!(compile_to_chunk("1 + 2 = 3;").is_none());
+        assert!(compile_to_chunk("var;").is_none());
+    }
+
+    #[test]
+    fn compile_diagnostics_test() {
+        let diagnostics = compile_errors("1 +;\nprint (2;\nvar 3;\n#");
+        let errors: Vec<&Diagnostic> = diagnostics.iter().collect();
+
+        assert_eq!(errors.len(), 4);
+        assert_eq!(errors[0].line, 1);
+        assert_eq!(errors[0].location, Location::At(";".to_string()));
+        assert_eq!(errors[0].message, "Expect expression.");
+        assert_eq!(errors[1].line, 2);
+        assert_eq!(errors[1].message, "Expect ')' after expression.");
+        assert_eq!(errors[2].line, 3);
+        assert_eq!(errors[2].message, "Expect variable name.");
+        assert_eq!(errors[3].location, Location::Unknown);
+        assert_eq!(errors[3].message, "Unexpected character.");
+    }
+
+    #[test]
+    fn compile_diagnostics_at_end_test() {
+        let diagnostics = compile_errors("print 1");
+
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(
+            diagnostics.to_string(),
+            "[line 1] Error at end: Expect ';' after value."
+        );
+    }
+
+    #[test]
+    fn compile_if_test() {
+        let chunk = compile_to_chunk("if (true) print 1; else print 2;").unwrap();
+
+        assert_eq!(
+            chunk.code(),
+            vec![
+                OpCode::True as u8,
+                OpCode::JumpIfFalse as u8,
+                0,
+                7,
+                OpCode::Pop as u8,
+                OpCode::Constant as u8,
+                0,
+                OpCode::Print as u8,
+                OpCode::Jump as u8,
+                0,
+                4,
+                OpCode::Pop as u8,
+                OpCode::Constant as u8,
+                1,
+                OpCode::Print as u8,
+                OpCode::Nil as u8,
+                OpCode::Return as u8,
+            ]
+        );
+
+        assert!(compile_to_chunk("if true print 1;").is_none());
+        assert!(compile_to_chunk("if (true print 1;").is_none());
+    }
+
+    #[test]
+    fn compile_too_much_code_to_jump_test() {
+        // Each `nil;` compiles to two bytes.
+        let body = "nil;".repeat(32_000);
+        assert!(compile_to_chunk(&format!("if (true) {{ {} }}", body)).is_some());
+        let body = "nil;".repeat(33_000);
+        assert!(compile_to_chunk(&format!("if (true) {{ {} }}", body)).is_none());
+    }
+
+    #[test]
+    fn compile_dump_bytecode_test() {
+        let mut strings = Interner::new();
+        let mut output = Vec::new();
+        let options = CompilerOptions {
+            dump_bytecode: true,
+            ..Default::default()
+        };
+
+        let mut errors = Vec::new();
+        compile_with_options(
+            "print nil;",
+            &mut strings,
+            &mut output,
+            &mut errors,
+            options,
+        )
+        .unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "== <script> ==\n0000  1 OP_NIL\n0001    | OP_PRINT\n0002    | OP_NIL\n0003    | OP_RETURN\n"
+        );
+
+        let mut output = Vec::new();
+        compile("print nil;", &mut strings, &mut output).unwrap();
+        assert!(output.is_empty());
+    }
+
+    #[test]
+    fn compile_with_symbols_test() {
+        let mut strings = Interner::new();
+        let mut output = Vec::new();
+        let source = "var a = 1; { var b = a; b = b + a; }";
+
+        let (result, symbols) = compile_with_symbols(
+            source,
+            &mut strings,
+            &mut output,
+            &mut Vec::new(),
+            CompilerOptions::default(),
+        );
+        assert!(result.is_ok());
+
+        let a = symbols.lookup("a").unwrap();
+        let b = symbols.lookup("b").unwrap();
+        assert_eq!(symbols.len(), 2);
+        assert_eq!(symbols.occurrences(a), [4..5, 21..22, 32..33]);
+        assert_eq!(symbols.occurrences(b), [17..18, 24..25, 28..29]);
+        assert_eq!(&source[symbols.occurrences(b)[2].clone()], "b");
+    }
+
+    #[test]
+    fn compile_with_symbols_errors_test() {
+        let mut strings = Interner::new();
+        let mut errors = Vec::new();
+        let source = "var a = 1; print a +;";
+
+        let (result, symbols) = compile_with_symbols(
+            source,
+            &mut strings,
+            &mut Vec::new(),
+            &mut errors,
+            CompilerOptions::default(),
+        );
+        assert!(result.is_err());
+        assert!(!errors.is_empty());
+
+        let a = symbols.lookup("a").unwrap();
+        assert_eq!(symbols.occurrences(a), [4..5, 17..18]);
+    }
+
+    fn compile_warnings(source: &str) -> Vec<Diagnostic> {
+        let mut strings = Interner::new();
+        let compiled = compile_with_options(
+            source,
+            &mut strings,
+            &mut Vec::new(),
+            &mut Vec::new(),
+            CompilerOptions::default(),
+        );
+
+        match compiled {
+            Ok(compiled) => compiled.diagnostics.iter().cloned().collect(),
+            Err(diagnostics) => panic!("Didn't compile: {}", diagnostics),
+        }
+    }
+
+    #[test]
+    fn compile_chained_comparison_test() {
+        let warnings = compile_warnings("1 < 2 < 3;");
+        assert_eq!(warnings.len(), 1);
+        assert_eq!(warnings[0].location, Location::At("<".to_string()));
+
+        assert_eq!(compile_warnings("1 < 2 + 3 >= 4;").len(), 1);
+        assert_eq!(compile_warnings("1 <= 2 > 3 < 4;").len(), 2);
+        assert!(compile_warnings("(1 < 2) < 3;").is_empty());
+        assert!(compile_warnings("1 < 2 == true;").is_empty());
+        assert!(compile_warnings("1 < 2; 3 < 4;").is_empty());
+        assert!(compile_warnings("1 == 2 == false;").is_empty());
+    }
+
+    #[test]
+    fn compile_while_test() {
+        let chunk = compile_to_chunk("while (false) nil;").unwrap();
+
+        assert_eq!(
+            chunk.code(),
+            vec![
+                OpCode::False as u8,
+                OpCode::JumpIfFalse as u8,
+                0,
+                6,
+                OpCode::Pop as u8,
+                OpCode::Nil as u8,
+                OpCode::Pop as u8,
+                OpCode::Loop as u8,
+                0,
+                10,
+                OpCode::Pop as u8,
+                OpCode::Nil as u8,
+                OpCode::Return as u8,
+            ]
+        );
+    }
+
+    #[test]
+    fn compile_for_test() {
+        assert!(compile_to_chunk("for (;;) nil;").is_some());
+        assert!(compile_to_chunk("for (var i = 0; i < 3; i = i + 1) print i;").is_some());
+        assert!(compile_to_chunk("var i; for (i = 0; i < 3;) i = i + 1;").is_some());
+        assert!(compile_to_chunk("for (var i = 0; i < 3) print i;").is_none());
+        assert!(compile_to_chunk("for var i = 0; i < 3; i = i + 1) print i;").is_none());
+        assert!(compile_to_chunk("for (var i = 0; i < 3; i = i + 1 print i;").is_none());
+    }
+
+    #[test]
+    fn compile_function_test() {
+        let chunk = compile_to_chunk("fun add(a, b) { return a + b; } add(1, 2);").unwrap();
+
+        let function = match chunk.constants.at(1) {
+            Value::Function(function) => function,
+            value => panic!("Expected a function constant, found {:?}", value),
+        };
+        assert_eq!(function.to_string(), "<fn add>");
+        assert_eq!(function.arity, 2);
+        assert_eq!(
+            function.chunk.code(),
+            vec![
+                OpCode::GetLocal as u8,
+                1,
+                OpCode::GetLocal as u8,
+                2,
+                OpCode::Add as u8,
+                OpCode::Return as u8,
+                OpCode::Nil as u8,
+                OpCode::Return as u8,
+            ]
+        );
+
+        assert_eq!(
+            chunk.code(),
+            vec![
+                OpCode::Constant as u8,
+                1,
+                OpCode::DefineGlobal as u8,
+                0,
+                OpCode::GetGlobal as u8,
+                2,
+                OpCode::Constant as u8,
+                3,
+                OpCode::Constant as u8,
+                4,
+                OpCode::Call as u8,
+                2,
+                OpCode::Pop as u8,
+                OpCode::Nil as u8,
+                OpCode::Return as u8,
+            ]
+        );
+    }
+
+    #[test]
+    fn compile_function_errors_test() {
+        assert!(compile_to_chunk("fun f() {}").is_some());
+        assert!(compile_to_chunk("{ fun f() { return f; } }").is_some());
+        assert!(compile_to_chunk("return 1;").is_none());
+        assert!(compile_to_chunk("fun () {}").is_none());
+        assert!(compile_to_chunk("fun f(a b) {}").is_none());
+        assert!(compile_to_chunk("fun f(a) return a;").is_none());
+        assert!(compile_to_chunk("f(1 2);").is_none());
+
+        let params: Vec<String> = (0..255).map(|i| format!("a{}", i)).collect();
+        assert!(compile_to_chunk(&format!("fun f({}) {{}}", params.join(", "))).is_some());
+        let params: Vec<String> = (0..256).map(|i| format!("a{}", i)).collect();
+        assert!(compile_to_chunk(&format!("fun f({}) {{}}", params.join(", "))).is_none());
+
+        let args = vec!["nil"; 255].join(", ");
+        assert!(compile_to_chunk(&format!("f({});", args)).is_some());
+        let args = vec!["nil"; 256].join(", ");
+        assert!(compile_to_chunk(&format!("f({});", args)).is_none());
+    }
+
+    #[test]
+    fn compile_object_test() {
+        let chunk = compile_to_chunk("print { x: 1, y: 2, }.y;").unwrap();
+
+        assert_eq!(
+            chunk.code(),
+            [
+                OpCode::Constant as u8,
+                0,
+                OpCode::Constant as u8,
+                1,
+                OpCode::Constant as u8,
+                2,
+                OpCode::Constant as u8,
+                3,
+                OpCode::Object as u8,
+                2,
+                OpCode::GetProperty as u8,
+                4,
+                OpCode::Print as u8,
+                OpCode::Nil as u8,
+                OpCode::Return as u8,
+            ]
+        );
+        assert!(compile_to_chunk("var empty = {};").is_some());
+        // At the start of a statement, braces are still a block.
+        assert!(compile_to_chunk("{ x: 1 };").is_none());
+        assert!(compile_to_chunk("print { x 1 };").is_none());
+        assert!(compile_to_chunk("print { \"x\": 1 };").is_none());
+        assert!(compile_to_chunk("var a = {}; a.x = 1;").is_none());
+        assert!(compile_to_chunk("print 1.;").is_none());
+
+        let diagnostics = compile_errors("print { x: 1, x: 2 };");
+        assert_eq!(
+            diagnostics.to_string(),
+            "[line 1] Error at 'x': Duplicate field 'x' in object literal."
+        );
+    }
+
+    #[test]
+    fn compile_unassigned_local_test() {
+        let warnings = compile_warnings("{ var a; print a; print a; }");
+        assert_eq!(warnings.len(), 1);
+        assert_eq!(warnings[0].location, Location::At("a".to_string()));
+
+        assert_eq!(
+            compile_warnings("{ var a; while (true) { print a; a = 1; } }").len(),
+            1
+        );
+        assert!(compile_warnings("{ var a = nil; print a; }").is_empty());
+        assert!(compile_warnings("{ var a; a = 1; print a; }").is_empty());
+        assert!(compile_warnings("fun f(a) { print a; }").is_empty());
+        assert!(compile_warnings("var a; print a;").is_empty());
+    }
+
+    #[test]
+    fn compile_unassigned_branches_test() {
+        // Without an else, the condition can skip the assignment.
+        let warnings = compile_warnings("fun f() { var x; if (false) x = 1; print x; }");
+        assert_eq!(warnings.len(), 1);
+        assert_eq!(
+            warnings[0].message,
+            "Local variable 'x' may be read before it's assigned; it will be nil."
+        );
+
+        assert_eq!(
+            compile_warnings("{ var x; if (true) x = 1; else print 2; print x; }").len(),
+            1
+        );
+        assert_eq!(
+            compile_warnings("{ var x; if (true) print 1; else x = 2; print x; }").len(),
+            1
+        );
+        assert_eq!(
+            compile_warnings("{ var x; while (false) x = 1; print x; }").len(),
+            1
+        );
+        assert_eq!(
+            compile_warnings("{ var x; for (;false;) x = 1; print x; }").len(),
+            1
+        );
+        assert_eq!(
+            compile_warnings("{ var x; for (;; x = 1) print x; }").len(),
+            1
+        );
+
+        assert!(compile_warnings("{ var x; if (true) x = 1; else x = 2; print x; }").is_empty());
+        assert!(compile_warnings("{ var x; if (true) { x = 1; print x; } }").is_empty());
+        assert!(compile_warnings("{ var x; x = 1; while (false) print x; }").is_empty());
+    }
+
+    #[test]
+    fn compile_strict_test() {
+        let mut strings = Interner::new();
+        let mut errors = Vec::new();
+        let options = CompilerOptions {
+            strict: true,
+            ..Default::default()
+        };
+        let source = "{ var x; if (false) x = 1; print x; }";
+
+        let diagnostics =
+            compile_with_options(source, &mut strings, &mut Vec::new(), &mut errors, options)
+                .err()
+                .unwrap();
+        assert_eq!(
+            diagnostics.to_string(),
+            "[line 1] Error at 'x': Local variable 'x' may be read before it's assigned; it will be nil."
+        );
+
+        assert!(compile_with_options(
+            "{ var x; x = 1; print x; }",
+            &mut strings,
+            &mut Vec::new(),
+            &mut errors,
+            options,
+        )
+        .is_ok());
+    }
+
+    #[test]
+    fn compile_json_errors_test() {
+        let mut strings = Interner::new();
+        let mut errors = Vec::new();
+        let options = CompilerOptions {
+            error_format: ErrorFormat::Json,
+            ..Default::default()
+        };
+
+        let diagnostics = compile_with_options(
+            "1 +;\nprint 2",
+            &mut strings,
+            &mut Vec::new(),
+            &mut errors,
+            options,
+        )
+        .err()
+        .unwrap();
+
+        let lines: Vec<String> = diagnostics.iter().map(Diagnostic::to_json).collect();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(String::from_utf8(errors).unwrap(), lines.join("\n") + "\n");
+        assert_eq!(
+            lines[1],
+            "{\"severity\":\"error\",\"line\":2,\"at_end\":true,\"lexeme\":null,\"message\":\"Expect ';' after value.\"}"
+        );
+    }
+
+    #[test]
+    fn explain_precedence_test() {
+        let mut output = Vec::new();
+        explain_precedence(&mut output);
+
+        let output = String::from_utf8(output).unwrap();
+        let lines: Vec<&str> = output.lines().collect();
+
+        assert_eq!(
+            lines,
+            [
+                "Level  Name        Associativity  Operators",
+                "1      Assignment  right          =",
+                "2      Or",
+                "3      And",
+                "4      Equality    left           != ==",
+                "5      Comparison  left           > >= < <=",
+                "6      Term        left           - +",
+                "7      Factor      left           / *",
+                "8      Unary       prefix         - !",
+                "9      Call        left           ( .",
+                "10     Primary",
+            ]
+        );
+    }
 }
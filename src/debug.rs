@@ -1,36 +1,93 @@
-#![allow(dead_code)]
-
-use crate::chunk::{Chunk, OpCode};
+use crate::chunk::{Chunk, OpCode, OperandKind};
+use crate::scanner::{ScanError, Scanner};
+use crate::value::{ObjFunction, Value};
 use std::io::Write;
 
-pub fn _disassemble_chunk<W: Write>(chunk: &Chunk, name: &str, writer: &mut W) {
+pub fn print_tokens<W: Write>(source: &str, writer: &mut W) {
+    let mut line: usize = usize::MAX;
+
+    for result in Scanner::new(source) {
+        match result {
+            Ok(token) => {
+                if token.line != line {
+                    write!(writer, "{:4} ", token.line).unwrap();
+                    line = token.line;
+                } else {
+                    write!(writer, "   | ").unwrap();
+                }
+
+                writeln!(
+                    writer,
+                    "{:2} '{}'",
+                    token.token_type as i32,
+                    token.lexeme(source)
+                )
+                .unwrap();
+            }
+            Err(err) => match err {
+                ScanError::UnexpectedChar { line } => {
+                    writeln!(writer, "Unexpected char on line: {}", line).unwrap();
+                }
+                ScanError::UnterminatedString { line } => {
+                    writeln!(writer, "Unterminated string on line: {}", line).unwrap();
+                }
+                ScanError::MalformedNumber { line } => {
+                    writeln!(writer, "Malformed number on line: {}", line).unwrap();
+                }
+            },
+        }
+    }
+}
+
+pub fn disassemble_chunk<W: Write + ?Sized>(chunk: &Chunk, name: &str, writer: &mut W) {
     writeln!(writer, "== {} ==", name).unwrap();
 
     let mut offset = 0;
-    while offset < chunk.code.len() {
+    while offset < chunk.len() {
         offset = disassemble_instruction(chunk, offset, writer);
     }
 }
 
-pub fn disassemble_instruction<W: Write>(chunk: &Chunk, offset: usize, writer: &mut W) -> usize {
+/// Disassembles `function`, followed by every function declared inside it.
+pub fn disassemble_function<W: Write + ?Sized>(function: &ObjFunction, writer: &mut W) {
+    let chunk = &function.chunk;
+    disassemble_chunk(chunk, &function.to_string(), writer);
+
+    for i in 0..chunk.constants.len() {
+        if let Value::Function(nested) = chunk.constants.at(i) {
+            disassemble_function(&nested, writer);
+        }
+    }
+}
+
+pub fn disassemble_instruction<W: Write + ?Sized>(
+    chunk: &Chunk,
+    offset: usize,
+    writer: &mut W,
+) -> usize {
     write!(writer, "{:04} ", offset).unwrap();
 
-    if offset > 0 && chunk.lines[offset] == chunk.lines[offset - 1] {
+    let line = chunk.get_line(offset).expect("Index out of bounds");
+    if offset > 0 && chunk.get_line(offset - 1) == Some(line) {
         write!(writer, "   | ").unwrap();
     } else {
-        write!(writer, " {} ", chunk.lines[offset]).unwrap();
+        write!(writer, " {} ", line).unwrap();
     }
 
-    let instruction = *chunk.code.get(offset).expect("Index out of bounds");
+    let instruction = chunk.get_byte(offset).expect("Index out of bounds");
 
     match OpCode::try_from(instruction) {
-        Ok(OpCode::Constant) => constant_instruction("OP_CONSTANT", chunk, offset, writer),
-        Ok(OpCode::Add) => simple_instruction("OP_ADD", offset, writer),
-        Ok(OpCode::Subtract) => simple_instruction("OP_SUBTRACT", offset, writer),
-        Ok(OpCode::Multiply) => simple_instruction("OP_MULTIPLY", offset, writer),
-        Ok(OpCode::Divide) => simple_instruction("OP_DIVIDE", offset, writer),
-        Ok(OpCode::Negate) => simple_instruction("OP_NEGATE", offset, writer),
-        Ok(OpCode::Return) => simple_instruction("OP_RETURN", offset, writer),
+        Ok(op) => {
+            let info = op.info();
+            match info.operands {
+                [] => simple_instruction(info.name, offset, writer),
+                [OperandKind::Constant] => constant_instruction(info.name, chunk, offset, writer),
+                [OperandKind::Byte] => byte_instruction(info.name, chunk, offset, writer),
+                [OperandKind::Jump] => jump_instruction(info.name, 1, chunk, offset, writer),
+                [OperandKind::Loop] => jump_instruction(info.name, -1, chunk, offset, writer),
+                _ => unreachable!("No disassembler for operands of {}", info.name),
+            }
+        }
         Err(_) => {
             writeln!(writer, "Unknown opcode: {:?}", instruction).unwrap();
             offset + 1
@@ -38,20 +95,63 @@ pub fn disassemble_instruction<W: Write>(chunk: &Chunk, offset: usize, writer: &
     }
 }
 
-fn simple_instruction<W: Write>(name: &str, offset: usize, writer: &mut W) -> usize {
+/// Prints what's left of an instruction whose operands run past the end of
+/// the chunk, and returns the end so disassembly stops there.
+fn truncated_instruction<W: Write + ?Sized>(name: &str, chunk: &Chunk, writer: &mut W) -> usize {
+    writeln!(writer, "{}         <truncated>", name).unwrap();
+    chunk.len()
+}
+
+fn simple_instruction<W: Write + ?Sized>(name: &str, offset: usize, writer: &mut W) -> usize {
     writeln!(writer, "{}", name).unwrap();
     offset + 1
 }
 
-fn constant_instruction<W: Write>(
+fn byte_instruction<W: Write + ?Sized>(
     name: &str,
     chunk: &Chunk,
     offset: usize,
     writer: &mut W,
 ) -> usize {
-    let constant = chunk.code[offset + 1];
+    let slot = match chunk.get_byte(offset + 1) {
+        Some(slot) => slot,
+        None => return truncated_instruction(name, chunk, writer),
+    };
+    writeln!(writer, "{}         {}", name, slot).unwrap();
+    offset + 2
+}
+
+fn jump_instruction<W: Write + ?Sized>(
+    name: &str,
+    sign: isize,
+    chunk: &Chunk,
+    offset: usize,
+    writer: &mut W,
+) -> usize {
+    let jump = match (chunk.get_byte(offset + 1), chunk.get_byte(offset + 2)) {
+        (Some(high), Some(low)) => u16::from_be_bytes([high, low]),
+        _ => return truncated_instruction(name, chunk, writer),
+    };
+    let target = (offset + 3) as isize + sign * jump as isize;
+    writeln!(writer, "{}         {} -> {}", name, offset, target).unwrap();
+    offset + 3
+}
+
+fn constant_instruction<W: Write + ?Sized>(
+    name: &str,
+    chunk: &Chunk,
+    offset: usize,
+    writer: &mut W,
+) -> usize {
+    let constant = match chunk.get_byte(offset + 1) {
+        Some(constant) => constant,
+        None => return truncated_instruction(name, chunk, writer),
+    };
     write!(writer, "{}         {} ", name, constant).unwrap();
-    write!(writer, "'{}'", chunk.constants.at(constant as usize)).unwrap();
+    match chunk.constants.get(constant as usize) {
+        Some(value) => write!(writer, "'{}'", value).unwrap(),
+        None => write!(writer, "<bad constant {}>", constant).unwrap(),
+    }
     writeln!(writer).unwrap();
     offset + 2
 }
@@ -59,6 +159,26 @@ fn constant_instruction<W: Write>(
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::value::Value;
+
+    #[test]
+    fn print_tokens_test() {
+        let source = "print 1 +\n2;".to_string();
+
+        let mut output = Vec::new();
+        print_tokens(&source, &mut output);
+
+        let output_str = String::from_utf8(output).unwrap();
+
+        let expectation = "   1 31 'print'\n\
+    \x20  | 21 '1'\n\
+    \x20  |  7 '+'\n\
+    \x20  2 21 '2'\n\
+    \x20  |  8 ';'\n\
+    \x20  | 39 ''\n";
+
+        assert_eq!(output_str, expectation);
+    }
 
     #[test]
     fn disassemble_op_return_test() {
@@ -66,7 +186,7 @@ mod tests {
         chunk.write(OpCode::Return as u8, 123);
 
         let mut output = Vec::new();
-        _disassemble_chunk(&chunk, "test chunk", &mut output);
+        disassemble_chunk(&chunk, "test chunk", &mut output);
 
         let output_str = String::from_utf8(output).unwrap();
 
@@ -80,14 +200,14 @@ mod tests {
     fn disassemble_op_constant_test() {
         let mut chunk = Chunk::new();
 
-        let constant = chunk.add_constant(1.2);
+        let constant = chunk.add_constant(Value::Number(1.2));
         chunk.write(OpCode::Constant as u8, 123);
         chunk.write(constant as u8, 123);
 
         chunk.write(OpCode::Return as u8, 123);
 
         let mut output = Vec::new();
-        _disassemble_chunk(&chunk, "test chunk", &mut output);
+        disassemble_chunk(&chunk, "test chunk", &mut output);
 
         let output_str = String::from_utf8(output).unwrap();
 
@@ -102,7 +222,7 @@ mod tests {
     fn disassemble_op_negate_test() {
         let mut chunk = Chunk::new();
 
-        let constant = chunk.add_constant(1.2);
+        let constant = chunk.add_constant(Value::Number(1.2));
         chunk.write(OpCode::Constant as u8, 123);
         chunk.write(constant as u8, 123);
         chunk.write(OpCode::Negate as u8, 123);
@@ -110,7 +230,7 @@ mod tests {
         chunk.write(OpCode::Return as u8, 123);
 
         let mut output = Vec::new();
-        _disassemble_chunk(&chunk, "test chunk", &mut output);
+        disassemble_chunk(&chunk, "test chunk", &mut output);
 
         let output_str = String::from_utf8(output).unwrap();
 
@@ -126,11 +246,11 @@ mod tests {
     fn disassemble_op_add_test() {
         let mut chunk = Chunk::new();
 
-        let mut constant = chunk.add_constant(1.2);
+        let mut constant = chunk.add_constant(Value::Number(1.2));
         chunk.write(OpCode::Constant as u8, 123);
         chunk.write(constant as u8, 123);
 
-        constant = chunk.add_constant(5.3);
+        constant = chunk.add_constant(Value::Number(5.3));
         chunk.write(OpCode::Constant as u8, 123);
         chunk.write(constant as u8, 123);
 
@@ -139,7 +259,7 @@ mod tests {
         chunk.write(OpCode::Return as u8, 123);
 
         let mut output = Vec::new();
-        _disassemble_chunk(&chunk, "test chunk", &mut output);
+        disassemble_chunk(&chunk, "test chunk", &mut output);
 
         let output_str = String::from_utf8(output).unwrap();
 
@@ -156,11 +276,11 @@ mod tests {
     fn disassemble_op_subtract_test() {
         let mut chunk = Chunk::new();
 
-        let mut constant = chunk.add_constant(1.2);
+        let mut constant = chunk.add_constant(Value::Number(1.2));
         chunk.write(OpCode::Constant as u8, 123);
         chunk.write(constant as u8, 123);
 
-        constant = chunk.add_constant(5.3);
+        constant = chunk.add_constant(Value::Number(5.3));
         chunk.write(OpCode::Constant as u8, 123);
         chunk.write(constant as u8, 123);
 
@@ -169,7 +289,7 @@ mod tests {
         chunk.write(OpCode::Return as u8, 123);
 
         let mut output = Vec::new();
-        _disassemble_chunk(&chunk, "test chunk", &mut output);
+        disassemble_chunk(&chunk, "test chunk", &mut output);
 
         let output_str = String::from_utf8(output).unwrap();
 
@@ -186,11 +306,11 @@ mod tests {
     fn disassemble_op_multiply_test() {
         let mut chunk = Chunk::new();
 
-        let mut constant = chunk.add_constant(1.2);
+        let mut constant = chunk.add_constant(Value::Number(1.2));
         chunk.write(OpCode::Constant as u8, 123);
         chunk.write(constant as u8, 123);
 
-        constant = chunk.add_constant(5.3);
+        constant = chunk.add_constant(Value::Number(5.3));
         chunk.write(OpCode::Constant as u8, 123);
         chunk.write(constant as u8, 123);
 
@@ -199,7 +319,7 @@ mod tests {
         chunk.write(OpCode::Return as u8, 123);
 
         let mut output = Vec::new();
-        _disassemble_chunk(&chunk, "test chunk", &mut output);
+        disassemble_chunk(&chunk, "test chunk", &mut output);
 
         let output_str = String::from_utf8(output).unwrap();
 
@@ -216,11 +336,11 @@ mod tests {
     fn disassemble_op_divide_test() {
         let mut chunk = Chunk::new();
 
-        let mut constant = chunk.add_constant(1.2);
+        let mut constant = chunk.add_constant(Value::Number(1.2));
         chunk.write(OpCode::Constant as u8, 123);
         chunk.write(constant as u8, 123);
 
-        constant = chunk.add_constant(5.3);
+        constant = chunk.add_constant(Value::Number(5.3));
         chunk.write(OpCode::Constant as u8, 123);
         chunk.write(constant as u8, 123);
 
@@ -229,7 +349,7 @@ mod tests {
         chunk.write(OpCode::Return as u8, 123);
 
         let mut output = Vec::new();
-        _disassemble_chunk(&chunk, "test chunk", &mut output);
+        disassemble_chunk(&chunk, "test chunk", &mut output);
 
         let output_str = String::from_utf8(output).unwrap();
 
@@ -241,4 +361,118 @@ mod tests {
 
         assert_eq!(output_str, expectation);
     }
+
+    #[test]
+    fn disassemble_op_equal_test() {
+        let mut chunk = Chunk::new();
+
+        chunk.write(OpCode::True as u8, 123);
+        chunk.write(OpCode::Nil as u8, 123);
+        chunk.write(OpCode::Equal as u8, 123);
+        chunk.write(OpCode::Not as u8, 124);
+        chunk.write(OpCode::Return as u8, 124);
+
+        let mut output = Vec::new();
+        disassemble_chunk(&chunk, "test chunk", &mut output);
+
+        let output_str = String::from_utf8(output).unwrap();
+
+        let expectation = "== test chunk ==\n\
+    0000  123 OP_TRUE\n\
+    0001    | OP_NIL\n\
+    0002    | OP_EQUAL\n\
+    0003  124 OP_NOT\n\
+    0004    | OP_RETURN\n";
+
+        assert_eq!(output_str, expectation);
+    }
+
+    #[test]
+    fn disassemble_variable_test() {
+        let mut chunk = Chunk::new();
+
+        let constant = chunk.add_constant(Value::Nil);
+        chunk.write(OpCode::Nil as u8, 123);
+        chunk.write(OpCode::DefineGlobal as u8, 123);
+        chunk.write(constant as u8, 123);
+        chunk.write(OpCode::GetLocal as u8, 124);
+        chunk.write(1, 124);
+        chunk.write(OpCode::Print as u8, 124);
+
+        let mut output = Vec::new();
+        disassemble_chunk(&chunk, "test chunk", &mut output);
+
+        let output_str = String::from_utf8(output).unwrap();
+
+        let expectation = "== test chunk ==\n\
+    0000  123 OP_NIL\n\
+    0001    | OP_DEFINE_GLOBAL         0 'nil'\n\
+    0003  124 OP_GET_LOCAL         1\n\
+    0005    | OP_PRINT\n";
+
+        assert_eq!(output_str, expectation);
+    }
+
+    #[test]
+    fn disassemble_jump_test() {
+        let mut chunk = Chunk::new();
+
+        chunk.write(OpCode::True as u8, 1);
+        chunk.write(OpCode::JumpIfFalse as u8, 1);
+        chunk.write(0, 1);
+        chunk.write(1, 1);
+        chunk.write(OpCode::Pop as u8, 1);
+        chunk.write(OpCode::Jump as u8, 2);
+        chunk.write(1, 2);
+        chunk.write(0, 2);
+        chunk.write(OpCode::Loop as u8, 2);
+        chunk.write(0, 2);
+        chunk.write(11, 2);
+        chunk.write(OpCode::Return as u8, 2);
+
+        let mut output = Vec::new();
+        disassemble_chunk(&chunk, "test chunk", &mut output);
+
+        let output_str = String::from_utf8(output).unwrap();
+
+        let expectation = "== test chunk ==\n\
+    0000  1 OP_TRUE\n\
+    0001    | OP_JUMP_IF_FALSE         1 -> 5\n\
+    0004    | OP_POP\n\
+    0005  2 OP_JUMP         5 -> 264\n\
+    0008    | OP_LOOP         8 -> 0\n\
+    0011    | OP_RETURN\n";
+
+        assert_eq!(output_str, expectation);
+    }
+
+    #[test]
+    fn disassemble_corrupted_test() {
+        let mut chunk = Chunk::new();
+
+        chunk.write(OpCode::Constant as u8, 1);
+        chunk.write(5, 1);
+        chunk.write(OpCode::Jump as u8, 1);
+        chunk.write(0, 1);
+
+        let mut output = Vec::new();
+        disassemble_chunk(&chunk, "test chunk", &mut output);
+
+        let output_str = String::from_utf8(output).unwrap();
+
+        let expectation = "== test chunk ==\n\
+    0000  1 OP_CONSTANT         5 <bad constant 5>\n\
+    0002    | OP_JUMP         <truncated>\n";
+
+        assert_eq!(output_str, expectation);
+
+        let mut chunk = Chunk::new();
+        chunk.write(OpCode::GetLocal as u8, 1);
+
+        let mut output = Vec::new();
+        disassemble_chunk(&chunk, "test chunk", &mut output);
+
+        let output_str = String::from_utf8(output).unwrap();
+        assert!(output_str.ends_with("OP_GET_LOCAL         <truncated>\n"));
+    }
 }
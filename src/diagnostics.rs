@@ -0,0 +1,209 @@
+use std::fmt;
+
+/// Where in the source line a compile error points.
+#[derive(Clone, PartialEq, Debug)]
+pub enum Location {
+    /// At a specific token, holding its lexeme.
+    At(String),
+    /// At the end of the source.
+    AtEnd,
+    /// The scanner couldn't produce a token, so there's nothing to point at.
+    Unknown,
+}
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum Severity {
+    Error,
+    /// Suspicious code that still compiles.
+    Warning,
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Severity::Error => write!(f, "Error"),
+            Severity::Warning => write!(f, "Warning"),
+        }
+    }
+}
+
+/// How the compiler writes each error and warning as it finds it.
+#[derive(Copy, Clone, Default, PartialEq, Debug)]
+pub enum ErrorFormat {
+    /// The human-readable form `Diagnostic`'s `Display` produces.
+    #[default]
+    Text,
+    /// One JSON object per line, for editors and other tools.
+    Json,
+}
+
+/// A single compile error or warning.
+#[derive(Clone, PartialEq, Debug)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub line: usize,
+    pub location: Location,
+    pub message: String,
+}
+
+impl Diagnostic {
+    pub fn to_json(&self) -> String {
+        let lexeme = match &self.location {
+            Location::At(lexeme) => json_string(lexeme),
+            Location::AtEnd | Location::Unknown => "null".to_string(),
+        };
+
+        format!(
+            "{{\"severity\":\"{}\",\"line\":{},\"at_end\":{},\"lexeme\":{},\"message\":{}}}",
+            self.severity.to_string().to_lowercase(),
+            self.line,
+            self.location == Location::AtEnd,
+            lexeme,
+            json_string(&self.message)
+        )
+    }
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "[line {}] {}", self.line, self.severity)?;
+
+        match &self.location {
+            Location::At(lexeme) => write!(f, " at '{}'", lexeme)?,
+            Location::AtEnd => write!(f, " at end")?,
+            Location::Unknown => {}
+        }
+
+        write!(f, ": {}", self.message)
+    }
+}
+
+/// Every error and warning reported while compiling one piece of source, in
+/// the order they were found.
+#[derive(Clone, PartialEq, Debug, Default)]
+pub struct Diagnostics {
+    entries: Vec<Diagnostic>,
+}
+
+impl Diagnostics {
+    pub fn push(&mut self, diagnostic: Diagnostic) {
+        self.entries.push(diagnostic);
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn has_errors(&self) -> bool {
+        self.entries
+            .iter()
+            .any(|diagnostic| diagnostic.severity == Severity::Error)
+    }
+
+    pub fn iter(&self) -> std::slice::Iter<'_, Diagnostic> {
+        self.entries.iter()
+    }
+}
+
+impl fmt::Display for Diagnostics {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (i, diagnostic) in self.entries.iter().enumerate() {
+            if i > 0 {
+                writeln!(f)?;
+            }
+            write!(f, "{}", diagnostic)?;
+        }
+
+        Ok(())
+    }
+}
+
+fn json_string(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+
+    out.push('"');
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn diagnostic(line: usize, location: Location, message: &str) -> Diagnostic {
+        Diagnostic {
+            severity: Severity::Error,
+            line,
+            location,
+            message: message.to_string(),
+        }
+    }
+
+    #[test]
+    fn display_test() {
+        let mut diagnostics = Diagnostics::default();
+        diagnostics.push(diagnostic(
+            1,
+            Location::At(";".to_string()),
+            "Expect expression.",
+        ));
+        diagnostics.push(diagnostic(2, Location::AtEnd, "Expect ';' after value."));
+        diagnostics.push(diagnostic(3, Location::Unknown, "Unexpected character."));
+
+        assert_eq!(
+            diagnostics.to_string(),
+            "[line 1] Error at ';': Expect expression.\n\
+             [line 2] Error at end: Expect ';' after value.\n\
+             [line 3] Error: Unexpected character."
+        );
+    }
+
+    #[test]
+    fn to_json_test() {
+        let quoted = diagnostic(
+            4,
+            Location::At("\"a\\b\"".to_string()),
+            "Expect expression.",
+        );
+        assert_eq!(
+            quoted.to_json(),
+            "{\"severity\":\"error\",\"line\":4,\"at_end\":false,\"lexeme\":\"\\\"a\\\\b\\\"\",\"message\":\"Expect expression.\"}"
+        );
+
+        let at_end = diagnostic(5, Location::AtEnd, "Expect '}' after block.");
+        assert_eq!(
+            at_end.to_json(),
+            "{\"severity\":\"error\",\"line\":5,\"at_end\":true,\"lexeme\":null,\"message\":\"Expect '}' after block.\"}"
+        );
+    }
+
+    #[test]
+    fn warning_test() {
+        let mut diagnostics = Diagnostics::default();
+        let mut warning = diagnostic(1, Location::At("<".to_string()), "Careful.");
+        warning.severity = Severity::Warning;
+        diagnostics.push(warning);
+
+        assert!(!diagnostics.has_errors());
+        assert_eq!(diagnostics.to_string(), "[line 1] Warning at '<': Careful.");
+
+        diagnostics.push(diagnostic(2, Location::AtEnd, "Expect expression."));
+        assert!(diagnostics.has_errors());
+    }
+}
@@ -0,0 +1,29 @@
+//! Things a VM reports to its host as it works, for piping into the host's
+//! own logging or metrics.
+//!
+//! Objects are reference-counted and freed as soon as they're unused, so
+//! there are no collection cycles to report.
+
+use std::time::Duration;
+
+#[derive(Clone, PartialEq, Debug)]
+pub enum VmEvent<'a> {
+    /// The VM is about to compile `source`.
+    CompileStarted { source: &'a str },
+    /// Compiling finished, with or without errors.
+    CompileFinished { duration: Duration, succeeded: bool },
+    /// A run stopped with a runtime error. `trace` has a line for each call
+    /// that was running, innermost first.
+    RuntimeError {
+        message: &'a str,
+        trace: &'a [String],
+    },
+    /// A Lox function has just been called `calls` times, the VM's hot call
+    /// threshold. Each function reports this once.
+    FunctionHot { name: &'a str, calls: u32 },
+}
+
+/// Receives a VM's events. Set one with `VM::set_event_sink`.
+pub trait VmEventSink {
+    fn event(&mut self, event: &VmEvent);
+}
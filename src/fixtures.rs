@@ -0,0 +1,159 @@
+use rustlox::chunk::OpCode;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+#[cfg(test)]
+const EXPECT: &str = "// expect: ";
+
+/// Every opcode, in numeric order.
+pub fn opcodes() -> impl Iterator<Item = OpCode> {
+    (0..=u8::MAX).map_while(|byte| OpCode::try_from(byte).ok())
+}
+
+/// A small Lox program that exercises `op`, with `// expect:` comments
+/// giving what it prints. The match is exhaustive so a new opcode can't be
+/// added without a fixture. Opcodes the VM rewrites code into appear once
+/// the fixture has run.
+pub fn fixture(op: OpCode) -> &'static str {
+    match op {
+        OpCode::Constant => "print 1.5; // expect: 1.5\n",
+        OpCode::Add => "print 1 + 2; // expect: 3\nprint \"a\" + \"b\"; // expect: ab\n",
+        OpCode::Subtract => "print 5 - 3; // expect: 2\n",
+        OpCode::Multiply => "print 4 * 2.5; // expect: 10\n",
+        OpCode::Divide => "print 9 / 4; // expect: 2.25\n",
+        OpCode::Negate => "var a = 3;\nprint -a; // expect: -3\n",
+        OpCode::Return => "fun f() {\n  return 7;\n}\nprint f(); // expect: 7\n",
+        OpCode::Nil => "print nil; // expect: nil\n",
+        OpCode::True => "print true; // expect: true\n",
+        OpCode::False => "print false; // expect: false\n",
+        OpCode::Not => "print !true; // expect: false\nprint !nil; // expect: true\n",
+        OpCode::Equal => "print 1 == 1; // expect: true\nprint \"a\" == \"b\"; // expect: false\n",
+        OpCode::Greater => "print 2 > 1; // expect: true\n",
+        OpCode::Less => "print 2 < 1; // expect: false\n",
+        OpCode::Pop => "1 + 2;\nprint \"done\"; // expect: done\n",
+        OpCode::GetLocal => "{\n  var a = 1;\n  print a; // expect: 1\n}\n",
+        OpCode::SetLocal => "{\n  var a = 1;\n  a = 2;\n  print a; // expect: 2\n}\n",
+        OpCode::GetGlobal => "var a = \"global\";\nprint a; // expect: global\n",
+        OpCode::DefineGlobal => "var a;\nprint a; // expect: nil\n",
+        OpCode::SetGlobal => "var a = 1;\na = 2;\nprint a; // expect: 2\n",
+        OpCode::Print => "print \"hi\"; // expect: hi\n",
+        OpCode::Jump => "if (false) print 1; else print 2; // expect: 2\n",
+        OpCode::JumpIfFalse => "if (true) print 1; // expect: 1\n",
+        OpCode::Loop => {
+            "var i = 0;\n\
+             while (i < 3) {\n  print i;\n  i = i + 1;\n}\n\
+             // expect: 0\n// expect: 1\n// expect: 2\n"
+        }
+        OpCode::Call => "fun add(a, b) {\n  print a + b;\n}\nadd(1, 2); // expect: 3\n",
+        OpCode::AddNumber => {
+            "var total = 0;\n\
+             for (var i = 0; i < 20; i = i + 1) total = total + i;\n\
+             print total; // expect: 190\n"
+        }
+        OpCode::Object => "print { x: 1, y: \"two\" }; // expect: { x: 1, y: \"two\" }\n",
+        OpCode::GetProperty => "var point = { x: 3, y: 4 };\nprint point.y; // expect: 4\n",
+    }
+}
+
+/// The output a fixture expects, one entry per `// expect:` comment.
+#[cfg(test)]
+pub fn expectations(source: &str) -> Vec<&str> {
+    source
+        .lines()
+        .filter_map(|line| line.find(EXPECT).map(|i| &line[i + EXPECT.len()..]))
+        .collect()
+}
+
+/// Writes one fixture per opcode into `dir`, named after the opcode, and
+/// returns the paths written.
+pub fn generate(dir: &Path) -> io::Result<Vec<PathBuf>> {
+    fs::create_dir_all(dir)?;
+
+    opcodes()
+        .map(|op| {
+            let path = dir.join(format!("{}.lox", op.info().name.to_lowercase()));
+            fs::write(&path, fixture(op))?;
+            Ok(path)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rustlox::chunk::Chunk;
+    use rustlox::value::Value;
+    use rustlox::vm::{InterpretResult, VM};
+    use std::rc::Rc;
+
+    fn uses_opcode(chunk: &Chunk, op: OpCode) -> bool {
+        let mut offset = 0;
+        while offset < chunk.len() {
+            let instruction = OpCode::try_from(chunk.byte(offset)).unwrap();
+            if instruction as u8 == op as u8 {
+                return true;
+            }
+            offset += instruction.info().length();
+        }
+
+        (0..chunk.constants.len()).any(|i| match chunk.constants.at(i) {
+            Value::Function(function) => uses_opcode(&function.chunk, op),
+            _ => false,
+        })
+    }
+
+    #[test]
+    fn expectations_test() {
+        assert_eq!(
+            expectations("print 1; // expect: 1\n// expect: two words\nprint 3;"),
+            ["1", "two words"]
+        );
+    }
+
+    #[test]
+    fn every_opcode_covered_test() {
+        for op in opcodes() {
+            let source = fixture(op);
+            let name = op.info().name;
+
+            let mut vm = VM::new();
+            let function = vm
+                .compile(source, &mut Vec::new())
+                .ok()
+                .unwrap_or_else(|| panic!("{} fixture doesn't compile", name));
+            let function = Rc::new(function.function);
+
+            let mut output = Vec::new();
+            assert_eq!(
+                vm.run_function(function.clone(), &mut output),
+                InterpretResult::Ok,
+                "{} fixture failed",
+                name
+            );
+            assert!(
+                uses_opcode(&function.chunk, op),
+                "{} fixture doesn't use it",
+                name
+            );
+
+            let output = String::from_utf8(output).unwrap();
+            let lines: Vec<&str> = output.lines().collect();
+            assert_eq!(lines, expectations(source), "{} fixture output", name);
+        }
+    }
+
+    #[test]
+    fn generate_test() {
+        let dir = std::env::temp_dir().join(format!("rustlox-gen-tests-{}", std::process::id()));
+
+        let paths = generate(&dir).unwrap();
+        assert_eq!(paths.len(), opcodes().count());
+        assert_eq!(
+            fs::read_to_string(dir.join("op_add.lox")).unwrap(),
+            fixture(OpCode::Add)
+        );
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
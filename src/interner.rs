@@ -0,0 +1,82 @@
+use crate::table::PrehashedHasher;
+use crate::value::{hash_string, ObjString};
+use std::collections::HashMap;
+use std::hash::BuildHasherDefault;
+use std::rc::Rc;
+
+/// Deduplicates strings so every distinct string exists exactly once, which
+/// lets string equality be a pointer comparison.
+///
+/// Strings are bucketed by their FNV-1a hash, so interning hashes the
+/// characters once and the resulting `ObjString` keeps that hash.
+#[derive(Default)]
+pub struct Interner {
+    strings: HashMap<u32, Vec<Rc<ObjString>>, BuildHasherDefault<PrehashedHasher>>,
+}
+
+impl Interner {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Returns the canonical string object for `chars`, creating it if it
+    /// has not been seen before.
+    pub fn intern(&mut self, chars: &str) -> Rc<ObjString> {
+        let hash = hash_string(chars);
+        let bucket = self.strings.entry(hash).or_default();
+
+        if let Some(interned) = bucket.iter().find(|string| &*string.chars == chars) {
+            return interned.clone();
+        }
+
+        let string = Rc::new(ObjString::with_hash(chars, hash));
+        bucket.push(string.clone());
+        string
+    }
+
+    /// Drops strings that nothing outside the interner refers to anymore.
+    pub fn sweep(&mut self) {
+        self.strings.retain(|_, bucket| {
+            bucket.retain(|string| Rc::strong_count(string) > 1);
+            !bucket.is_empty()
+        });
+    }
+
+    pub fn len(&self) -> usize {
+        self.strings.values().map(Vec::len).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.strings.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn intern_test() {
+        let mut interner = Interner::new();
+
+        let a = interner.intern("lox");
+        let b = interner.intern("lox");
+        let c = interner.intern("clox");
+
+        assert!(Rc::ptr_eq(&a, &b));
+        assert!(!Rc::ptr_eq(&a, &c));
+        assert_eq!(interner.len(), 2);
+    }
+
+    #[test]
+    fn sweep_test() {
+        let mut interner = Interner::new();
+
+        let kept = interner.intern("kept");
+        interner.intern("dropped");
+        interner.sweep();
+
+        assert_eq!(interner.len(), 1);
+        assert!(Rc::ptr_eq(&kept, &interner.intern("kept")));
+    }
+}
@@ -0,0 +1,46 @@
+//! A bytecode interpreter for Lox.
+//!
+//! To run Lox from another program, make a `VM`, optionally add the
+//! built-in functions with `natives::define_all`, and hand it source:
+//!
+//! ```
+//! use rustlox::{InterpretResult, VM};
+//!
+//! let mut vm = VM::new();
+//! rustlox::natives::define_all(&mut vm);
+//!
+//! let mut output = Vec::new();
+//! let result = vm.interpret("print 1 + 2;".to_string(), &mut output);
+//!
+//! assert_eq!(result, InterpretResult::Ok);
+//! assert_eq!(output, b"3\n");
+//! ```
+//!
+//! `compile` turns source into a script function without running it, and
+//! `serialize` saves one as a `.loxc` file that `VM::load` reads back.
+
+pub mod chunk;
+pub mod compiler;
+pub mod debug;
+pub mod diagnostics;
+pub mod events;
+pub mod interner;
+mod nanbox;
+pub mod natives;
+pub mod repl;
+pub mod scanner;
+pub mod serialize;
+pub mod symbols;
+mod table;
+pub mod value;
+pub mod vm;
+
+pub use chunk::Chunk;
+pub use compiler::{compile, compile_with_options, Compiled, CompilerOptions};
+pub use diagnostics::{Diagnostics, ErrorFormat};
+pub use events::{VmEvent, VmEventSink};
+pub use interner::Interner;
+pub use serialize::FormatError;
+pub use symbols::{Symbol, SymbolTable};
+pub use value::{ObjFunction, Value};
+pub use vm::{InterpretResult, RunMetrics, TraceOptions, VmError, VM};
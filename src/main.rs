@@ -1,53 +1,181 @@
-use crate::vm::{InterpretResult, VM};
-use std::io::Write;
-use std::{env, fs, io, process::exit};
+use crate::cli::{Command, Options};
+use rustlox::compiler::CompilerOptions;
+use rustlox::interner::Interner;
+use rustlox::vm::{InterpretResult, VM};
+use rustlox::{compiler, debug, natives, repl, serialize};
+use std::io::{self, BufWriter, Write};
+use std::rc::Rc;
+use std::{env, fs, path::Path, process::exit};
 
-mod chunk;
-mod compiler;
-mod debug;
-mod scanner;
-mod value;
-mod vm;
+mod cli;
+mod fixtures;
 
 fn main() {
-    let args: Vec<String> = env::args().collect();
+    let mut cli = match cli::parse(env::args().skip(1), env::var("RUSTLOX_PRELUDE").ok()) {
+        Ok(cli) => cli,
+        Err(err) => {
+            eprintln!("{}\n\n{}", err, cli::USAGE);
+            exit(64);
+        }
+    };
+
+    cli.options.apply_env(
+        env::var_os("DEBUG_TRACE_EXECUTION").is_some(),
+        env::var_os("DEBUG_PRINT_CODE").is_some(),
+    );
+
+    match &cli.command {
+        Command::Help => return println!("{}", cli::USAGE),
+        Command::Version => return println!("{}", cli::version()),
+        Command::ExplainPrecedence => return compiler::explain_precedence(&mut io::stdout()),
+        Command::GenTests { dir } => return gen_tests(dir),
+        Command::Compile { path, output } => return compile_file(path, output, &cli.options),
+        Command::Disassemble { path } => return disassemble_file(path, &cli.options),
+        Command::Tokens { path } => {
+            return debug::print_tokens(&read_file(path), &mut io::stdout())
+        }
+        Command::Run { .. } | Command::Repl => {}
+    }
+
+    // Only the commands that run Lox get here, so only they run the
+    // prelude.
     let mut vm = VM::new();
+    natives::define_all(&mut vm);
+    vm.set_trace_options(cli.options.trace.clone());
+    vm.set_compiler_options(cli.options.compiler);
 
-    if args.len() == 1 {
-        repl(&mut vm);
-    } else if args.len() == 2 {
-        run_file(&args[1], &mut vm);
-    } else {
-        eprintln!("Usage:...");
-        exit(64);
+    if let Some(path) = &cli.options.prelude {
+        run_file(path, &mut vm, &Options::default());
+    }
+
+    match &cli.command {
+        Command::Run { path } => run_file(path, &mut vm, &cli.options),
+        Command::Repl => repl::run(&mut vm, &mut io::stdin().lock(), &mut stdout(&cli.options)),
+        Command::Help
+        | Command::Version
+        | Command::ExplainPrecedence
+        | Command::GenTests { .. }
+        | Command::Compile { .. }
+        | Command::Disassemble { .. }
+        | Command::Tokens { .. } => {
+            unreachable!()
+        }
     }
 }
 
-fn repl(vm: &mut VM) {
-    loop {
-        let mut input = String::new();
+/// Runs a script, or a compiled `.loxc` file if that's what `path` holds.
+fn run_file(path: &String, vm: &mut VM, options: &Options) {
+    let bytes = read_bytes(path);
+    let result = if serialize::is_compiled(&bytes) {
+        let script = vm.load(&bytes).unwrap_or_else(|e| {
+            eprintln!("Error loading {}: {}", path, e);
+            exit(65);
+        });
+        vm.run_function(Rc::new(script), &mut stdout(options))
+    } else {
+        let source = String::from_utf8(bytes).unwrap_or_else(|e| {
+            eprintln!("Error reading file: {}", e);
+            exit(74);
+        });
+        vm.interpret(source, &mut stdout(options))
+    };
 
-        print!("> ");
-        io::stdout().flush().unwrap();
+    if options.time {
+        eprintln!("{}", vm.last_run_metrics());
+    }
 
-        io::stdin()
-            .read_line(&mut input)
-            .expect("Failed to read line");
+    if result == InterpretResult::CompileError {
+        exit(65);
+    }
 
-        vm.interpret(input, &mut std::io::stdout());
+    if result == InterpretResult::RuntimeError {
+        exit(70);
+    }
+}
+
+/// Where scripts print to. The VM flushes it at the end of every run, and
+/// the REPL before every prompt.
+fn stdout(options: &Options) -> Box<dyn Write> {
+    if options.buffered {
+        Box::new(BufWriter::new(io::stdout()))
+    } else {
+        Box::new(io::stdout())
     }
 }
 
-fn run_file(path: &String, vm: &mut VM) {
+/// Compiles a script and saves it to `output` without running it.
+fn compile_file(path: &String, output: &String, options: &Options) {
+    let mut strings = Interner::new();
     let source = read_file(path);
-    let result = vm.interpret(source, &mut std::io::stdout());
+    let compiled = compiler::compile_with_options(
+        &source,
+        &mut strings,
+        &mut io::stdout(),
+        &mut io::stderr(),
+        options.compiler,
+    );
+    let script = match compiled {
+        Ok(compiled) => compiled.function,
+        Err(_) => exit(65),
+    };
 
-    if result == InterpretResult::CompileError {
+    let bytes = serialize::serialize(&script).unwrap_or_else(|e| {
+        eprintln!("Error compiling {}: {}", path, e);
         exit(65);
+    });
+    if let Err(e) = fs::write(output, bytes) {
+        eprintln!("Error writing file: {}", e);
+        exit(74);
     }
+}
 
-    if result == InterpretResult::RuntimeError {
-        exit(70);
+/// Prints the bytecode for a script, or a compiled `.loxc` file, and every
+/// function in it, without running anything.
+fn disassemble_file(path: &String, options: &Options) {
+    let bytes = read_bytes(path);
+    let mut strings = Interner::new();
+
+    let script = if serialize::is_compiled(&bytes) {
+        serialize::deserialize(&bytes, &mut strings).unwrap_or_else(|e| {
+            eprintln!("Error loading {}: {}", path, e);
+            exit(65);
+        })
+    } else {
+        let source = String::from_utf8(bytes).unwrap_or_else(|e| {
+            eprintln!("Error reading file: {}", e);
+            exit(74);
+        });
+        let compiled = compiler::compile_with_options(
+            &source,
+            &mut strings,
+            &mut io::stdout(),
+            &mut io::stderr(),
+            // This prints the bytecode anyway.
+            CompilerOptions {
+                dump_bytecode: false,
+                ..options.compiler
+            },
+        );
+        match compiled {
+            Ok(compiled) => compiled.function,
+            Err(_) => exit(65),
+        }
+    };
+
+    debug::disassemble_function(&script, &mut io::stdout());
+}
+
+fn gen_tests(dir: &String) {
+    match fixtures::generate(Path::new(dir)) {
+        Ok(paths) => {
+            for path in paths {
+                println!("{}", path.display());
+            }
+        }
+        Err(e) => {
+            eprintln!("Error writing fixtures: {}", e);
+            exit(74);
+        }
     }
 }
 
@@ -60,3 +188,13 @@ fn read_file(path: &String) -> String {
         }
     }
 }
+
+fn read_bytes(path: &String) -> Vec<u8> {
+    match fs::read(path) {
+        Ok(bytes) => bytes,
+        Err(e) => {
+            eprintln!("Error reading file: {}", e);
+            exit(74);
+        }
+    }
+}
@@ -0,0 +1,242 @@
+//! A `Value` packed into a single `u64` using NaN boxing.
+//!
+//! Numbers are stored as their own bits. Everything else hides in the
+//! unused payload of a quiet NaN: `nil` and the booleans as small tags, and
+//! objects as a pointer with the sign bit set. Object pointers come from
+//! `Rc::into_raw`, so a box owns one strong reference to its object.
+
+use crate::value::{ObjFunction, ObjNative, ObjObject, ObjString, Value};
+use std::any::Any;
+use std::fmt;
+use std::rc::Rc;
+
+const SIGN_BIT: u64 = 0x8000_0000_0000_0000;
+const QNAN: u64 = 0x7ffc_0000_0000_0000;
+
+const NIL: u64 = QNAN | 1;
+const FALSE: u64 = QNAN | 2;
+const TRUE: u64 = QNAN | 3;
+
+/// Object pointers fit in 48 bits, and are 8-byte aligned because an `Rc`'s
+/// value follows its two reference counts. The free low bits say what kind
+/// of object it is.
+const ADDRESS_MASK: u64 = 0x0000_ffff_ffff_fff8;
+const KIND_MASK: u64 = 0b111;
+
+const KIND_STRING: u64 = 0;
+const KIND_FUNCTION: u64 = 1;
+const KIND_NATIVE: u64 = 2;
+/// `Rc<dyn Any>` is a fat pointer, so foreign values are boxed once more
+/// and the box's thin pointer is stored instead.
+const KIND_FOREIGN: u64 = 3;
+const KIND_OBJECT: u64 = 4;
+
+pub struct NanBox(u64);
+
+impl NanBox {
+    fn is_number(&self) -> bool {
+        self.0 & QNAN != QNAN
+    }
+
+    fn is_object(&self) -> bool {
+        self.0 & (SIGN_BIT | QNAN) == SIGN_BIT | QNAN
+    }
+
+    fn object<T>(pointer: *const T, kind: u64) -> Self {
+        let address = pointer as u64;
+        assert_eq!(
+            address & !ADDRESS_MASK,
+            0,
+            "Object pointer doesn't fit in a NaN box"
+        );
+        NanBox(SIGN_BIT | QNAN | address | kind)
+    }
+
+    fn address(&self) -> u64 {
+        self.0 & ADDRESS_MASK
+    }
+
+    /// A copy of the boxed value. Objects gain a reference.
+    pub fn to_value(&self) -> Value {
+        if self.is_number() {
+            return Value::Number(f64::from_bits(self.0));
+        }
+
+        if !self.is_object() {
+            return match self.0 {
+                NIL => Value::Nil,
+                FALSE => Value::Bool(false),
+                TRUE => Value::Bool(true),
+                _ => unreachable!("Unknown NaN box tag {:#x}", self.0),
+            };
+        }
+
+        let address = self.address();
+        // SAFETY: The address came from `Rc::into_raw` for the type its kind
+        // names, or from `Box::into_raw` for foreign values, and this box
+        // still holds the reference it took then. Incrementing the count
+        // before `from_raw` gives the new `Rc` its own reference.
+        unsafe {
+            match self.0 & KIND_MASK {
+                KIND_STRING => Value::String(clone_rc(address as *const ObjString)),
+                KIND_FUNCTION => Value::Function(clone_rc(address as *const ObjFunction)),
+                KIND_NATIVE => Value::Native(clone_rc(address as *const ObjNative)),
+                KIND_FOREIGN => Value::Foreign((*(address as *const Rc<dyn Any>)).clone()),
+                KIND_OBJECT => Value::Object(clone_rc(address as *const ObjObject)),
+                kind => unreachable!("Unknown NaN box object kind {}", kind),
+            }
+        }
+    }
+}
+
+/// # Safety
+///
+/// `pointer` must have come from `Rc::into_raw` and its reference must still
+/// be held.
+unsafe fn clone_rc<T>(pointer: *const T) -> Rc<T> {
+    Rc::increment_strong_count(pointer);
+    Rc::from_raw(pointer)
+}
+
+impl From<Value> for NanBox {
+    fn from(value: Value) -> Self {
+        match value {
+            // Arithmetic can produce NaNs with any payload, and some of those
+            // look like boxed values. Every NaN is equally not a number.
+            Value::Number(n) if n.is_nan() => NanBox(f64::NAN.to_bits()),
+            Value::Number(n) => NanBox(n.to_bits()),
+            Value::Nil => NanBox(NIL),
+            Value::Bool(false) => NanBox(FALSE),
+            Value::Bool(true) => NanBox(TRUE),
+            Value::String(string) => Self::object(Rc::into_raw(string), KIND_STRING),
+            Value::Function(function) => Self::object(Rc::into_raw(function), KIND_FUNCTION),
+            Value::Native(native) => Self::object(Rc::into_raw(native), KIND_NATIVE),
+            Value::Foreign(foreign) => Self::object(Box::into_raw(Box::new(foreign)), KIND_FOREIGN),
+            Value::Object(object) => Self::object(Rc::into_raw(object), KIND_OBJECT),
+        }
+    }
+}
+
+impl From<&NanBox> for Value {
+    fn from(boxed: &NanBox) -> Self {
+        boxed.to_value()
+    }
+}
+
+impl Clone for NanBox {
+    fn clone(&self) -> Self {
+        NanBox::from(self.to_value())
+    }
+}
+
+impl Drop for NanBox {
+    fn drop(&mut self) {
+        if !self.is_object() {
+            return;
+        }
+
+        let address = self.address();
+        // SAFETY: This box owns the reference `From<Value>` took, and gives
+        // it back exactly once here.
+        unsafe {
+            match self.0 & KIND_MASK {
+                KIND_STRING => drop(Rc::from_raw(address as *const ObjString)),
+                KIND_FUNCTION => drop(Rc::from_raw(address as *const ObjFunction)),
+                KIND_NATIVE => drop(Rc::from_raw(address as *const ObjNative)),
+                KIND_FOREIGN => drop(Box::from_raw(address as *mut Rc<dyn Any>)),
+                KIND_OBJECT => drop(Rc::from_raw(address as *const ObjObject)),
+                kind => unreachable!("Unknown NaN box object kind {}", kind),
+            }
+        }
+    }
+}
+
+impl fmt::Debug for NanBox {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "NanBox({:?})", self.to_value())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::interner::Interner;
+    use std::mem::size_of;
+
+    fn round_trip(value: Value) -> Value {
+        NanBox::from(value).to_value()
+    }
+
+    #[test]
+    fn size_test() {
+        assert_eq!(size_of::<NanBox>(), 8);
+        assert!(size_of::<Value>() > size_of::<NanBox>());
+    }
+
+    #[test]
+    fn round_trip_test() {
+        for n in [0.0, -0.0, 1.5, -3.0, f64::INFINITY, f64::MIN_POSITIVE] {
+            match round_trip(Value::Number(n)) {
+                Value::Number(m) => assert_eq!(m.to_bits(), n.to_bits()),
+                value => panic!("{:?} came back as {:?}", n, value),
+            }
+        }
+
+        assert_eq!(round_trip(Value::Nil), Value::Nil);
+        assert_eq!(round_trip(Value::Bool(true)), Value::Bool(true));
+        assert_eq!(round_trip(Value::Bool(false)), Value::Bool(false));
+
+        let mut strings = Interner::new();
+        let string = Value::String(strings.intern("lox"));
+        assert_eq!(round_trip(string.clone()), string);
+
+        let function = Value::Function(Rc::new(ObjFunction::new(None)));
+        assert_eq!(round_trip(function.clone()), function);
+
+        let object = Value::Object(Rc::new(ObjObject {
+            fields: vec![(strings.intern("x"), Value::Number(1.0))],
+        }));
+        assert_eq!(round_trip(object.clone()), object);
+
+        let foreign = Value::foreign(String::from("handle"));
+        let back = round_trip(foreign.clone());
+        assert_eq!(back, foreign);
+        assert_eq!(back.as_foreign::<String>().unwrap(), "handle");
+    }
+
+    #[test]
+    fn nan_test() {
+        let weird = f64::from_bits(QNAN | SIGN_BIT | 8);
+        assert!(weird.is_nan());
+
+        match round_trip(Value::Number(weird)) {
+            Value::Number(n) => assert!(n.is_nan()),
+            value => panic!("NaN came back as {:?}", value),
+        }
+    }
+
+    #[test]
+    fn reference_count_test() {
+        let string = Rc::new(ObjString::new("counted"));
+        let foreign: Rc<dyn Any> = Rc::new(7);
+
+        let boxed = NanBox::from(Value::String(string.clone()));
+        let boxed_foreign = NanBox::from(Value::Foreign(foreign.clone()));
+        assert_eq!(Rc::strong_count(&string), 2);
+        assert_eq!(Rc::strong_count(&foreign), 2);
+
+        let copy = boxed.clone();
+        let copy_foreign = boxed_foreign.clone();
+        let value = copy.to_value();
+        assert_eq!(Rc::strong_count(&string), 4);
+        assert_eq!(Rc::strong_count(&foreign), 3);
+
+        drop(value);
+        drop(copy);
+        drop(boxed);
+        drop(copy_foreign);
+        drop(boxed_foreign);
+        assert_eq!(Rc::strong_count(&string), 1);
+        assert_eq!(Rc::strong_count(&foreign), 1);
+    }
+}
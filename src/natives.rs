@@ -0,0 +1,213 @@
+//! Built-in functions available to every Lox program.
+
+use crate::value::Value;
+use crate::vm::VM;
+use std::io::Write;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+pub fn define_all(vm: &mut VM) {
+    vm.define_native("clock", clock);
+    vm.define_native("hash", hash);
+    vm.define_vm_native("bench", bench);
+    vm.define_vm_native("timeIt", time_it);
+    vm.define_vm_native("flush", flush);
+    vm.define_vm_native("version", version);
+}
+
+/// Seconds since the Unix epoch.
+fn clock(_args: &[Value]) -> Value {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    Value::Number(now.as_secs_f64())
+}
+
+/// A number that's equal for equal values, for building hash tables in Lox.
+/// Strings reuse the hash cached when they were interned.
+fn hash(args: &[Value]) -> Value {
+    let hash = match args.first() {
+        Some(Value::String(string)) => string.hash,
+        Some(Value::Number(n)) => {
+            // Make 0 and -0, which are equal, hash the same.
+            let bits = if *n == 0.0 { 0 } else { n.to_bits() };
+            (bits ^ (bits >> 32)) as u32
+        }
+        Some(Value::Bool(b)) => *b as u32,
+        _ => return Value::Nil,
+    };
+
+    Value::Number(hash as f64)
+}
+
+/// The interpreter's version, like `"0.1.0"`, so scripts can check they're
+/// running on one new enough.
+fn version(vm: &mut VM, _args: &[Value], _writer: &mut dyn Write) -> Result<Value, String> {
+    Ok(Value::String(vm.intern(env!("CARGO_PKG_VERSION"))))
+}
+
+/// Writes out anything the program has printed that's still buffered.
+fn flush(_vm: &mut VM, _args: &[Value], writer: &mut dyn Write) -> Result<Value, String> {
+    writer
+        .flush()
+        .map_err(|err| format!("Couldn't flush output: {}", err))?;
+    Ok(Value::Nil)
+}
+
+/// `bench(fn, iterations)` calls `fn` with no arguments `iterations` times and
+/// returns the total elapsed milliseconds.
+fn bench(vm: &mut VM, args: &[Value], writer: &mut dyn Write) -> Result<Value, String> {
+    let (callee, iterations) = match args {
+        [callee @ (Value::Function(_) | Value::Native(_)), Value::Number(n)]
+            if *n >= 0.0 && n.fract() == 0.0 =>
+        {
+            (callee, *n as u64)
+        }
+        _ => return Err("bench() expects a function and an iteration count.".to_string()),
+    };
+
+    let start = Instant::now();
+    for _ in 0..iterations {
+        vm.call_sync(callee.clone(), &[], writer)?;
+    }
+
+    Ok(Value::Number(start.elapsed().as_secs_f64() * 1000.0))
+}
+
+/// `timeIt(fn)` calls `fn` with no arguments once and returns the elapsed
+/// milliseconds.
+fn time_it(vm: &mut VM, args: &[Value], writer: &mut dyn Write) -> Result<Value, String> {
+    let callee = match args {
+        [callee @ (Value::Function(_) | Value::Native(_))] => callee,
+        _ => return Err("timeIt() expects a function.".to_string()),
+    };
+
+    let start = Instant::now();
+    vm.call_sync(callee.clone(), &[], writer)?;
+
+    Ok(Value::Number(start.elapsed().as_secs_f64() * 1000.0))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::interner::Interner;
+    use crate::vm::InterpretResult;
+
+    #[test]
+    fn clock_test() {
+        match clock(&[]) {
+            Value::Number(secs) => assert!(secs > 0.0),
+            value => panic!("Expected a number, found {:?}", value),
+        }
+    }
+
+    fn interpret(source: &str) -> (InterpretResult, String) {
+        let mut vm = VM::new();
+        let mut output = Vec::new();
+        define_all(&mut vm);
+
+        let result = vm.interpret(source.to_string(), &mut output);
+        (result, String::from_utf8(output).unwrap())
+    }
+
+    #[test]
+    fn bench_test() {
+        let source = "
+            var calls = 0;
+            fun work() { calls = calls + 1; print calls; }
+            var ms = bench(work, 3);
+            print ms >= 0;
+            print bench(clock, 2) >= 0;
+            print bench(work, 0) >= 0;
+        ";
+
+        let (result, output) = interpret(source);
+        assert_eq!(result, InterpretResult::Ok);
+        assert_eq!(output, "1\n2\n3\ntrue\ntrue\ntrue\n");
+    }
+
+    #[test]
+    fn time_it_test() {
+        let source = "
+            fun work() { for (var i = 0; i < 10; i = i + 1) {} print \"done\"; }
+            print timeIt(work) >= 0;
+            fun outer() { print timeIt(work) >= 0; }
+            print timeIt(outer) >= 0;
+        ";
+
+        let (result, output) = interpret(source);
+        assert_eq!(result, InterpretResult::Ok);
+        assert_eq!(output, "done\ntrue\ndone\ntrue\ntrue\n");
+    }
+
+    #[test]
+    fn bench_errors_test() {
+        for source in [
+            "bench(1, 1);",
+            "bench(clock);",
+            "bench(clock, -1);",
+            "bench(clock, 1.5);",
+            "timeIt();",
+            "fun f(a) {} timeIt(f);",
+            "fun f() { return nil + 1; } timeIt(f);",
+            "fun f() { bench(f, 1); } f();",
+        ] {
+            let (result, _) = interpret(source);
+            assert_eq!(result, InterpretResult::RuntimeError, "source: {}", source);
+        }
+    }
+
+    #[test]
+    fn flush_test() {
+        /// Buffers everything, and records what was written at each flush.
+        #[derive(Default)]
+        struct Recorder {
+            pending: Vec<u8>,
+            flushed: Vec<String>,
+        }
+
+        impl Write for Recorder {
+            fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+                self.pending.extend_from_slice(buf);
+                Ok(buf.len())
+            }
+
+            fn flush(&mut self) -> std::io::Result<()> {
+                let pending = std::mem::take(&mut self.pending);
+                self.flushed.push(String::from_utf8(pending).unwrap());
+                Ok(())
+            }
+        }
+
+        let mut vm = VM::new();
+        let mut output = Recorder::default();
+        define_all(&mut vm);
+
+        let source = "print 1; print flush(); print 2;".to_string();
+        assert_eq!(vm.interpret(source, &mut output), InterpretResult::Ok);
+        assert_eq!(output.flushed, ["1\n", "nil\n2\n"]);
+    }
+
+    #[test]
+    fn version_test() {
+        let (result, output) = interpret("print version(); print version() == version();");
+        assert_eq!(result, InterpretResult::Ok);
+        assert_eq!(output, format!("{}\ntrue\n", env!("CARGO_PKG_VERSION")));
+    }
+
+    #[test]
+    fn hash_test() {
+        let mut strings = Interner::new();
+        let lox = strings.intern("lox");
+
+        assert_eq!(
+            hash(&[Value::String(lox.clone())]),
+            Value::Number(lox.hash as f64)
+        );
+        assert_eq!(hash(&[Value::Number(0.0)]), hash(&[Value::Number(-0.0)]));
+        assert_eq!(hash(&[Value::Number(1.5)]), hash(&[Value::Number(1.5)]));
+        assert_eq!(hash(&[Value::Bool(true)]), Value::Number(1.0));
+        assert_eq!(hash(&[Value::Nil]), Value::Nil);
+        assert_eq!(hash(&[]), Value::Nil);
+    }
+}
@@ -0,0 +1,394 @@
+use crate::compiler::{compile, compile_with_options, explain_precedence, CompilerOptions};
+use crate::debug::disassemble_function;
+use crate::interner::Interner;
+use crate::scanner::{ScanError, Scanner, TokenType};
+use crate::value::Value;
+use crate::vm::VM;
+use std::fs;
+use std::io::{self, BufRead, Write};
+
+const PROMPT: &str = "> ";
+/// Shown while the input so far has unclosed brackets or an unterminated
+/// string.
+const CONTINUATION_PROMPT: &str = "... ";
+
+const HELP: &str = "\
+:help          Show this list
+:load <path>   Run a file in this session
+:paste         Read lines verbatim until a lone '.', then run them
+:dis <code>    Print the bytecode for a global function or a statement
+:prec          Print the operator precedence table
+:reset         Forget every variable and function defined so far
+:quit          Leave the session";
+
+/// Runs an interactive session, reading lines from `input` until it runs
+/// out. Input runs as soon as its brackets and strings are closed, so
+/// globals persist between entries and a `{` at the end of a line carries
+/// the entry on to the next. An entry that's a bare expression, with no
+/// `;`, has its value printed. `:paste` takes anything verbatim.
+pub fn run<R: BufRead, W: Write>(vm: &mut VM, input: &mut R, output: &mut W) {
+    loop {
+        write!(output, "{}", PROMPT).unwrap();
+        output.flush().unwrap();
+
+        let line = match read_line(input) {
+            Some(line) => line,
+            None => {
+                writeln!(output).unwrap();
+                return;
+            }
+        };
+
+        match line.trim() {
+            ":paste" => {
+                writeln!(
+                    output,
+                    "// Entering paste mode (end with a lone '.' or Ctrl-D)"
+                )
+                .unwrap();
+                output.flush().unwrap();
+
+                let source = read_paste(input);
+                interpret(vm, source, output);
+            }
+            ":prec" => explain_precedence(output),
+            ":help" => writeln!(output, "{}", HELP).unwrap(),
+            ":reset" => vm.reset(),
+            ":quit" => return,
+            command if command.starts_with(":dis ") => {
+                disassemble(vm, command[":dis ".len()..].trim(), output)
+            }
+            command if command.starts_with(":load ") => {
+                load(vm, command[":load ".len()..].trim(), output)
+            }
+            command if command.starts_with(':') => {
+                writeln!(
+                    output,
+                    "Unknown command '{}'. Type :help for a list.",
+                    command
+                )
+                .unwrap();
+            }
+            _ => {
+                let source = read_continuation(line, input, output);
+                interpret(vm, echo(source), output);
+            }
+        }
+    }
+}
+
+/// Keeps reading lines onto `source` until it's complete, or the input
+/// ends.
+fn read_continuation<R: BufRead, W: Write>(
+    mut source: String,
+    input: &mut R,
+    output: &mut W,
+) -> String {
+    while !is_complete(&source) {
+        write!(output, "{}", CONTINUATION_PROMPT).unwrap();
+        output.flush().unwrap();
+
+        match read_line(input) {
+            Some(line) => source.push_str(&line),
+            None => break,
+        }
+    }
+
+    source
+}
+
+/// Whether `source` could be a whole entry: every `(` and `{` is closed and
+/// no string is left open. Extra closing brackets count as complete, so the
+/// compiler gets to report them.
+fn is_complete(source: &str) -> bool {
+    let mut depth: i32 = 0;
+
+    for result in Scanner::new(source) {
+        match result {
+            Ok(token) => match token.token_type {
+                TokenType::LeftParen | TokenType::LeftBrace => depth += 1,
+                TokenType::RightParen | TokenType::RightBrace => depth -= 1,
+                _ => {}
+            },
+            Err(ScanError::UnterminatedString { .. }) => return false,
+            Err(_) => {}
+        }
+    }
+
+    depth <= 0
+}
+
+/// Wraps `source` in a `print` statement if it's a single expression
+/// without a `;`. Anything else, including a statement missing its `;`, is
+/// left alone so the compiler reports it as usual. A leading `{` is a block,
+/// as it is at the start of any statement, even where it could also be an
+/// object literal.
+fn echo(source: String) -> String {
+    let tokens: Vec<TokenType> = Scanner::new(&source)
+        .filter_map(Result::ok)
+        .map(|token| token.token_type)
+        .collect();
+    if tokens.first() == Some(&TokenType::LeftBrace) || tokens.contains(&TokenType::Semicolon) {
+        return source;
+    }
+
+    // The `;` goes on its own line so a trailing `//` comment can't swallow
+    // it.
+    let wrapped = format!("print {}\n;\n", source.trim_end());
+    let compiled = compile_with_options(
+        &wrapped,
+        &mut Interner::new(),
+        &mut io::sink(),
+        &mut io::sink(),
+        CompilerOptions::default(),
+    );
+
+    if compiled.is_ok() {
+        wrapped
+    } else {
+        source
+    }
+}
+
+/// A runtime error only ends the line that caused it. The session keeps
+/// its globals and carries on.
+fn interpret<W: Write>(vm: &mut VM, source: String, output: &mut W) {
+    vm.interpret(source, output);
+
+    if vm.is_poisoned() {
+        vm.recover();
+    }
+}
+
+/// Runs a script file in the session, so what it defines stays available.
+fn load<W: Write>(vm: &mut VM, path: &str, output: &mut W) {
+    match fs::read_to_string(path) {
+        Ok(source) => interpret(vm, source, output),
+        Err(err) => writeln!(output, "Error reading {}: {}", path, err).unwrap(),
+    }
+}
+
+/// Prints the bytecode for a defined function, or for `code` compiled as a
+/// statement, without running anything.
+fn disassemble<W: Write>(vm: &mut VM, code: &str, output: &mut W) {
+    if let Some(Value::Function(function)) = vm.get_global(code) {
+        return disassemble_function(&function, output);
+    }
+
+    let source = if code.ends_with(';') || code.ends_with('}') {
+        code.to_string()
+    } else {
+        format!("{};", code)
+    };
+
+    // A throwaway interner, so nothing this compiles outlives the command.
+    if let Ok(compiled) = compile(&source, &mut Interner::new(), output) {
+        disassemble_function(&compiled.function, output);
+    }
+}
+
+/// Reads one line, including its newline. Returns `None` at end of input.
+fn read_line<R: BufRead>(input: &mut R) -> Option<String> {
+    let mut line = String::new();
+
+    match input.read_line(&mut line) {
+        Ok(0) => None,
+        Ok(_) => Some(line),
+        Err(err) => {
+            eprintln!("Error reading input: {}", err);
+            None
+        }
+    }
+}
+
+/// Collects lines verbatim until a line holding only `.`, or end of input.
+fn read_paste<R: BufRead>(input: &mut R) -> String {
+    let mut source = String::new();
+
+    while let Some(line) = read_line(input) {
+        if line.trim_end() == "." {
+            break;
+        }
+        source.push_str(&line);
+    }
+
+    source
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    fn run_session(input: &str) -> String {
+        let mut vm = VM::new();
+        let mut output = Vec::new();
+
+        run(&mut vm, &mut Cursor::new(input), &mut output);
+        String::from_utf8(output).unwrap()
+    }
+
+    #[test]
+    fn run_test() {
+        let output = run_session("var a = 1;\nprint a + 1;\n");
+        assert_eq!(output, "> > 2\n> \n");
+    }
+
+    #[test]
+    fn runtime_error_test() {
+        let output = run_session("var a = 1;\nprint -nil;\nprint a;\n");
+        assert_eq!(output, "> > > 1\n> \n");
+    }
+
+    #[test]
+    fn dis_expression_test() {
+        let output = run_session(":dis 1 + 2\n");
+
+        assert_eq!(
+            output,
+            "> == <script> ==\n\
+             0000  1 OP_CONSTANT         0 '1'\n\
+             0002    | OP_CONSTANT         1 '2'\n\
+             0004    | OP_ADD\n\
+             0005    | OP_POP\n\
+             0006    | OP_NIL\n\
+             0007    | OP_RETURN\n\
+             > \n"
+        );
+    }
+
+    #[test]
+    fn dis_function_test() {
+        let output = run_session("fun f() { print 1; }\n:dis f\nf();\n");
+
+        assert!(output.starts_with("> > == <fn f> ==\n0000  1 OP_CONSTANT"));
+        assert!(output.ends_with("OP_RETURN\n> 1\n> \n"));
+    }
+
+    #[test]
+    fn paste_test() {
+        let output =
+            run_session(":paste\nfun f(x) {\n  return x * 2;\n}\nprint f(2);\n.\nprint f(3);\n");
+
+        assert_eq!(
+            output,
+            "> // Entering paste mode (end with a lone '.' or Ctrl-D)\n4\n> 6\n> \n"
+        );
+    }
+
+    #[test]
+    fn prec_test() {
+        let output = run_session(":prec\n");
+
+        assert!(output.starts_with("> Level"));
+        assert!(output.contains("Factor      left           / *"));
+    }
+
+    #[test]
+    fn continuation_test() {
+        let output = run_session("fun f(x) {\n  return x * 2;\n}\nprint f(2);\n");
+        assert_eq!(output, "> ... ... > 4\n> \n");
+
+        let output = run_session("print \"a\nb\";\n");
+        assert_eq!(output, "> ... a\nb\n> \n");
+    }
+
+    #[test]
+    fn continuation_until_eof_test() {
+        // What was typed still runs, so the compiler reports what's missing.
+        let output = run_session("fun f() {\n");
+        assert_eq!(output, "> ... > \n");
+    }
+
+    #[test]
+    fn is_complete_test() {
+        assert!(is_complete("print 1;\n"));
+        assert!(is_complete(""));
+        assert!(is_complete("print (1));\n"));
+        assert!(is_complete("// {\n"));
+        assert!(is_complete("print \"{\";\n"));
+        assert!(!is_complete("fun f() {\n"));
+        assert!(!is_complete("print (1 +\n"));
+        assert!(!is_complete("print \"abc\n"));
+    }
+
+    #[test]
+    fn commands_test() {
+        let output = run_session(":help\n");
+        assert!(output.starts_with("> :help"));
+        assert!(output.contains(":quit"));
+
+        let output = run_session(":quit\nprint 1;\n");
+        assert_eq!(output, "> ");
+
+        let output = run_session(":frobnicate\n");
+        assert_eq!(
+            output,
+            "> Unknown command ':frobnicate'. Type :help for a list.\n> \n"
+        );
+    }
+
+    #[test]
+    fn reset_test() {
+        let output = run_session("var a = 1;\n:reset\nprint a;\nvar a = 2;\nprint a;\n");
+        assert_eq!(output, "> > > > > 2\n> \n");
+    }
+
+    #[test]
+    fn load_test() {
+        let path =
+            std::env::temp_dir().join(format!("rustlox-repl-load-{}.lox", std::process::id()));
+        fs::write(&path, "var loaded = \"yes\";\nprint \"loading\";\n").unwrap();
+
+        let output = run_session(&format!(":load {}\nprint loaded;\n", path.display()));
+        assert_eq!(output, "> loading\n> yes\n> \n");
+
+        fs::remove_file(path).unwrap();
+        let output = run_session(":load /nonexistent/file.lox\n");
+        assert!(output.starts_with("> Error reading /nonexistent/file.lox: "));
+        assert!(output.ends_with("\n> \n"));
+    }
+
+    #[test]
+    fn echo_test() {
+        let output = run_session("1 + 2\nvar a = \"b\";\na\n(a +\n \"c\")\n");
+        assert_eq!(output, "> 3\n> > b\n> ... bc\n> \n");
+
+        // A bare `{}` is an empty block, not an empty object.
+        let output = run_session("{}\n");
+        assert_eq!(output, "> > \n");
+
+        // Statements and expressions ending in `;` print nothing extra.
+        let output = run_session("1 + 2;\nprint 3;\n");
+        assert_eq!(output, "> > 3\n> \n");
+
+        let output = run_session("1 + 2 // sum\n");
+        assert_eq!(output, "> 3\n> \n");
+    }
+
+    #[test]
+    fn echo_source_test() {
+        assert_eq!(echo("1 + 2\n".to_string()), "print 1 + 2\n;\n");
+        assert_eq!(echo("a = 1".to_string()), "print a = 1\n;\n");
+        assert_eq!(
+            echo("1 + 2 // sum\n".to_string()),
+            "print 1 + 2 // sum\n;\n"
+        );
+        assert_eq!(echo("print 1\n".to_string()), "print 1\n");
+        assert_eq!(echo("var a = 1\n".to_string()), "var a = 1\n");
+        assert_eq!(echo("1; 2\n".to_string()), "1; 2\n");
+        assert_eq!(echo("\n".to_string()), "\n");
+        assert_eq!(echo("{}\n".to_string()), "{}\n");
+        assert_eq!(echo("{ a }\n".to_string()), "{ a }\n");
+        assert_eq!(
+            echo("({ a: 1 }).a\n".to_string()),
+            "print ({ a: 1 }).a\n;\n"
+        );
+    }
+
+    #[test]
+    fn paste_until_eof_test() {
+        let output = run_session(":paste\nprint 1;\nprint 2;");
+        assert!(output.ends_with("1\n2\n> \n"));
+    }
+}
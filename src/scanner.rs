@@ -1,4 +1,7 @@
 use num_enum::TryFromPrimitive;
+use std::collections::VecDeque;
+use std::fmt;
+use std::ops::Range;
 
 #[repr(u8)]
 #[derive(Copy, Clone, TryFromPrimitive, PartialEq, Debug)]
@@ -47,8 +50,61 @@ pub enum TokenType {
     While = 37,
     // Make EOF 39 to match the book, which has an extra token type
     Eof = 39,
+    // Not in the book: separates an object literal's field names from values
+    Colon = 40,
 }
 
+impl TokenType {
+    /// How the token is spelled in source, for tokens that are always
+    /// spelled the same way.
+    pub fn text(self) -> Option<&'static str> {
+        let text = match self {
+            TokenType::LeftParen => "(",
+            TokenType::RightParen => ")",
+            TokenType::LeftBrace => "{",
+            TokenType::RightBrace => "}",
+            TokenType::Comma => ",",
+            TokenType::Dot => ".",
+            TokenType::Minus => "-",
+            TokenType::Plus => "+",
+            TokenType::Semicolon => ";",
+            TokenType::Slash => "/",
+            TokenType::Star => "*",
+            TokenType::Colon => ":",
+            TokenType::Bang => "!",
+            TokenType::BangEqual => "!=",
+            TokenType::Equal => "=",
+            TokenType::EqualEqual => "==",
+            TokenType::Greater => ">",
+            TokenType::GreaterEqual => ">=",
+            TokenType::Less => "<",
+            TokenType::LessEqual => "<=",
+            TokenType::And => "and",
+            TokenType::Class => "class",
+            TokenType::Else => "else",
+            TokenType::False => "false",
+            TokenType::For => "for",
+            TokenType::Fun => "fun",
+            TokenType::If => "if",
+            TokenType::Nil => "nil",
+            TokenType::Or => "or",
+            TokenType::Print => "print",
+            TokenType::Return => "return",
+            TokenType::Super => "super",
+            TokenType::This => "this",
+            TokenType::True => "true",
+            TokenType::Var => "var",
+            TokenType::While => "while",
+            TokenType::Identifier | TokenType::String | TokenType::Number | TokenType::Eof => {
+                return None
+            }
+        };
+
+        Some(text)
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Debug)]
 pub struct Token {
     pub token_type: TokenType,
     pub start: usize,
@@ -65,12 +121,53 @@ impl Token {
             line,
         }
     }
+
+    /// Byte range of the token in the source it was scanned from.
+    pub fn span(&self) -> Range<usize> {
+        self.start..self.start + self.length
+    }
+
+    /// The token's text, borrowed from the source it was scanned from.
+    pub fn lexeme<'a>(&self, source: &'a str) -> &'a str {
+        &source[self.span()]
+    }
 }
 
-#[derive(Debug)]
+#[derive(Copy, Clone, PartialEq, Debug)]
 pub enum ScanError {
-    UnexpectedChar { line: usize },
-    UnterminatedString { line: usize },
+    UnexpectedChar {
+        line: usize,
+    },
+    UnterminatedString {
+        line: usize,
+    },
+    /// A number with nothing on one side of its decimal point, like `.5` or
+    /// `5.`.
+    MalformedNumber {
+        line: usize,
+    },
+}
+
+impl ScanError {
+    pub fn line(&self) -> usize {
+        match self {
+            ScanError::UnexpectedChar { line }
+            | ScanError::UnterminatedString { line }
+            | ScanError::MalformedNumber { line } => *line,
+        }
+    }
+}
+
+impl fmt::Display for ScanError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ScanError::UnexpectedChar { .. } => write!(f, "Unexpected character."),
+            ScanError::UnterminatedString { .. } => write!(f, "Unterminated string."),
+            ScanError::MalformedNumber { .. } => {
+                write!(f, "Number needs digits on both sides of the decimal point.")
+            }
+        }
+    }
 }
 
 #[derive(Default)]
@@ -79,10 +176,14 @@ pub struct Scanner {
     start: usize,
     current: usize,
     source: Vec<u8>,
+    finished: bool,
+    /// Tokens scanned ahead by `peek_nth`, which `scan_token` hands out
+    /// before scanning more.
+    lookahead: VecDeque<Result<Token, ScanError>>,
 }
 
 impl Scanner {
-    pub fn new(source: &String) -> Self {
+    pub fn new(source: &str) -> Self {
         Scanner {
             source: source.as_bytes().to_vec(),
             line: 1,
@@ -91,6 +192,29 @@ impl Scanner {
     }
 
     pub fn scan_token(&mut self) -> Result<Token, ScanError> {
+        match self.lookahead.pop_front() {
+            Some(result) => result,
+            None => self.scan_next(),
+        }
+    }
+
+    /// The token `scan_token` will return next, without consuming it.
+    pub fn peek_token(&mut self) -> Result<Token, ScanError> {
+        self.peek_nth(0)
+    }
+
+    /// The token `n` places after the next one, without consuming anything.
+    /// Past the end of the source, every token is `Eof`.
+    pub fn peek_nth(&mut self, n: usize) -> Result<Token, ScanError> {
+        while self.lookahead.len() <= n {
+            let result = self.scan_next();
+            self.lookahead.push_back(result);
+        }
+
+        self.lookahead[n]
+    }
+
+    fn scan_next(&mut self) -> Result<Token, ScanError> {
         self.skip_whitespace();
 
         self.start = self.current;
@@ -115,6 +239,11 @@ impl Scanner {
             b'}' => self.make_token(TokenType::RightBrace),
             b';' => self.make_token(TokenType::Semicolon),
             b',' => self.make_token(TokenType::Comma),
+            b':' => self.make_token(TokenType::Colon),
+            b'.' if is_digit(self.peek()) => {
+                self.number()?;
+                Err(ScanError::MalformedNumber { line: self.line })
+            }
             b'.' => self.make_token(TokenType::Dot),
             b'-' => self.make_token(TokenType::Minus),
             b'+' => self.make_token(TokenType::Plus),
@@ -215,6 +344,10 @@ impl Scanner {
             while is_digit(self.peek()) {
                 self.advance();
             }
+        } else if self.peek() == b'.' && !is_alpha(self.peek_next()) {
+            // A dot followed by a name is a method call on the number.
+            self.advance();
+            return Err(ScanError::MalformedNumber { line: self.line });
         }
 
         self.make_token(TokenType::Number)
@@ -302,13 +435,9 @@ impl Scanner {
                     self.line += 1;
                     self.advance();
                 }
-                b'/' => {
-                    if self.peek_next() == b'/' {
-                        while self.peek() != b'\n' && !self.is_at_end() {
-                            self.advance();
-                        }
-                    } else {
-                        return;
+                b'/' if self.peek_next() == b'/' => {
+                    while self.peek() != b'\n' && !self.is_at_end() {
+                        self.advance();
                     }
                 }
                 _ => return,
@@ -325,13 +454,43 @@ impl Scanner {
     }
 
     fn peek_next(&self) -> u8 {
-        if self.is_at_end() {
+        if self.current + 1 >= self.source.len() {
             return b'\0';
         }
         self.source[self.current + 1]
     }
 }
 
+/// Yields tokens up to and including `Eof`, then stops.
+impl Iterator for Scanner {
+    type Item = Result<Token, ScanError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.finished {
+            return None;
+        }
+
+        let result = self.scan_token();
+        if let Ok(Token {
+            token_type: TokenType::Eof,
+            ..
+        }) = result
+        {
+            self.finished = true;
+        }
+
+        Some(result)
+    }
+}
+
+fn is_digit(c: u8) -> bool {
+    c.is_ascii_digit()
+}
+
+fn is_alpha(c: u8) -> bool {
+    c.is_ascii_lowercase() || c.is_ascii_uppercase() || c == b'_'
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -349,7 +508,7 @@ mod tests {
 
     #[test]
     fn scan_basic_token_test() {
-        let source = "(){};,.-+/*! != = == < <= > >=".to_string();
+        let source = "(){};,.-+/*:! != = == < <= > >=".to_string();
         let mut scanner = Scanner::new(&source);
 
         let mut token: Token;
@@ -366,6 +525,7 @@ mod tests {
             TokenType::Plus,
             TokenType::Slash,
             TokenType::Star,
+            TokenType::Colon,
             TokenType::Bang,
             TokenType::BangEqual,
             TokenType::Equal,
@@ -456,6 +616,81 @@ mod tests {
         assert_eq!(token.token_type, TokenType::String);
     }
 
+    #[test]
+    fn lexeme_test() {
+        let source = "var answer = 42;".to_string();
+        let mut scanner = Scanner::new(&source);
+
+        let lexemes = ["var", "answer", "=", "42", ";", ""];
+
+        for lexeme in lexemes {
+            let token = scanner.scan_token().unwrap();
+            assert_eq!(token.lexeme(&source), lexeme);
+        }
+    }
+
+    #[test]
+    fn span_test() {
+        let source = "  \"hi\"".to_string();
+        let mut scanner = Scanner::new(&source);
+
+        let token = scanner.scan_token().unwrap();
+        assert_eq!(token.span(), 2..6);
+    }
+
+    #[test]
+    fn iterator_test() {
+        let source = "1 + 2".to_string();
+        let scanner = Scanner::new(&source);
+
+        let token_types: Vec<TokenType> =
+            scanner.map(|result| result.unwrap().token_type).collect();
+
+        assert_eq!(
+            token_types,
+            [
+                TokenType::Number,
+                TokenType::Plus,
+                TokenType::Number,
+                TokenType::Eof
+            ]
+        );
+    }
+
+    #[test]
+    fn peek_test() {
+        let source = "a = @ b".to_string();
+        let mut scanner = Scanner::new(&source);
+
+        assert_eq!(
+            scanner.peek_token().unwrap().token_type,
+            TokenType::Identifier
+        );
+        assert_eq!(
+            scanner.peek_nth(2),
+            Err(ScanError::UnexpectedChar { line: 1 })
+        );
+        assert_eq!(scanner.peek_nth(3).unwrap().span(), 6..7);
+        assert_eq!(scanner.peek_nth(5).unwrap().token_type, TokenType::Eof);
+
+        // Peeking doesn't consume, and the iterator sees the same tokens.
+        let a = scanner.scan_token().unwrap();
+        assert_eq!(a.lexeme(&source), "a");
+        assert_eq!(scanner.peek_token().unwrap().token_type, TokenType::Equal);
+        let rest: Vec<Result<TokenType, ScanError>> = scanner
+            .map(|result| result.map(|token| token.token_type))
+            .collect();
+        assert_eq!(
+            rest,
+            [
+                Ok(TokenType::Equal),
+                Err(ScanError::UnexpectedChar { line: 1 }),
+                Ok(TokenType::Identifier),
+                Ok(TokenType::Eof)
+            ]
+        );
+    }
+
     #[test]
     fn scan_unterminated_string_test() {
         let source = "\"Hello, world!".to_string();
@@ -468,6 +703,69 @@ mod tests {
         ));
     }
 
+    #[test]
+    fn scan_trailing_slash_test() {
+        let source = "1 /".to_string();
+        let mut scanner = Scanner::new(&source);
+
+        let token_types = [TokenType::Number, TokenType::Slash, TokenType::Eof];
+
+        for token_type in token_types {
+            let token = scanner.scan_token().unwrap();
+            assert_eq!(token.token_type, token_type);
+        }
+    }
+
+    #[test]
+    fn token_text_test() {
+        for byte in 0..=u8::MAX {
+            let Ok(token_type) = TokenType::try_from(byte) else {
+                continue;
+            };
+
+            if let Some(text) = token_type.text() {
+                let tokens: Vec<_> = Scanner::new(text).map(|token| token.unwrap()).collect();
+                assert_eq!(tokens[0].token_type, token_type, "text: {}", text);
+                assert_eq!(tokens[1].token_type, TokenType::Eof);
+            }
+        }
+    }
+
+    #[test]
+    fn scan_number_forms_test() {
+        let cases: [(&str, Result<&str, ScanError>); 9] = [
+            ("0", Ok("0")),
+            ("12", Ok("12")),
+            ("1.5", Ok("1.5")),
+            ("007.250", Ok("007.250")),
+            ("1.5.2", Ok("1.5")),
+            ("5.abs", Ok("5")),
+            (".5", Err(ScanError::MalformedNumber { line: 1 })),
+            ("5.", Err(ScanError::MalformedNumber { line: 1 })),
+            ("5.;", Err(ScanError::MalformedNumber { line: 1 })),
+        ];
+
+        for (source, expected) in cases {
+            let mut scanner = Scanner::new(source);
+            let result = scanner
+                .scan_token()
+                .map(|token| token.lexeme(source).to_string());
+
+            assert_eq!(result, expected.map(str::to_string), "source: {}", source);
+        }
+    }
+
+    #[test]
+    fn scan_malformed_number_recovers_test() {
+        let source = ".5 5. 6";
+        let tokens: Vec<_> = Scanner::new(source).collect();
+
+        assert_eq!(tokens.len(), 4);
+        assert!(tokens[0].is_err());
+        assert!(tokens[1].is_err());
+        assert_eq!(tokens[2].unwrap().lexeme(source), "6");
+    }
+
     #[test]
     fn scan_unexpected_char() {
         let source = "#".to_string();
@@ -477,11 +775,3 @@ mod tests {
         assert!(matches!(result, Err(ScanError::UnexpectedChar { line: 1 })));
     }
 }
-
-fn is_digit(c: u8) -> bool {
-    c.is_ascii_digit()
-}
-
-fn is_alpha(c: u8) -> bool {
-    c.is_ascii_lowercase() || c.is_ascii_uppercase() || c == b'_'
-}
@@ -0,0 +1,682 @@
+//! The `.loxc` format: a compiled script saved so it can run without the
+//! compiler.
+//!
+//! A file is the magic bytes `LOXC`, the `BYTECODE_VERSION` it was written
+//! with, and then the script's function. Integers are little-endian. A
+//! function is
+//!
+//! - its name: a `u8` flag, then the name as a string if the flag is 1
+//! - its arity as a `u8`
+//! - its code: a `u32` length, then the bytes
+//! - its line table: a `u32` count, then a `u32` offset and `u32` line for
+//!   each run, as `Chunk::line_runs` gives them
+//! - its constants: a `u32` count, then each constant as a tag byte and
+//!   the tag's payload
+//!
+//! A string is a `u32` length followed by that many bytes of UTF-8.
+
+use crate::chunk::{Chunk, OpCode, OperandKind, BYTECODE_VERSION};
+use crate::interner::Interner;
+use crate::value::{ObjFunction, Value};
+use std::fmt;
+use std::rc::Rc;
+
+pub const MAGIC: &[u8; 4] = b"LOXC";
+
+const TAG_NIL: u8 = 0;
+const TAG_FALSE: u8 = 1;
+const TAG_TRUE: u8 = 2;
+/// Followed by the number's `f64` bits.
+const TAG_NUMBER: u8 = 3;
+/// Followed by a string.
+const TAG_STRING: u8 = 4;
+/// Followed by a whole function.
+const TAG_FUNCTION: u8 = 5;
+
+/// How deeply functions may be nested in each other's constants. Reading
+/// recurses once per level, so a crafted file could otherwise overflow the
+/// stack.
+const MAX_NESTING: usize = 256;
+
+#[derive(PartialEq, Debug)]
+pub enum FormatError {
+    /// A constant that only exists at runtime, which the compiler never
+    /// puts in a chunk.
+    Unsupported {
+        value: String,
+    },
+    NotCompiled,
+    WrongVersion {
+        version: u32,
+    },
+    Truncated,
+    UnknownTag {
+        tag: u8,
+    },
+    InvalidString,
+    InvalidLines,
+    TrailingBytes,
+    /// The top-level script takes arguments, which nothing can pass it.
+    ScriptArity {
+        arity: usize,
+    },
+    TooDeep,
+    UnknownOpcode {
+        offset: usize,
+        byte: u8,
+    },
+    /// An instruction's operands run past the end of the code.
+    TruncatedInstruction {
+        offset: usize,
+    },
+    ConstantOutOfRange {
+        offset: usize,
+        index: usize,
+    },
+    JumpOutOfRange {
+        offset: usize,
+    },
+}
+
+impl fmt::Display for FormatError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            FormatError::Unsupported { value } => write!(f, "Can't save constant {}", value),
+            FormatError::NotCompiled => write!(f, "Not a compiled Lox file"),
+            FormatError::WrongVersion { version } => write!(
+                f,
+                "Compiled for bytecode format {}, but this is format {}",
+                version, BYTECODE_VERSION
+            ),
+            FormatError::Truncated => write!(f, "File ends early"),
+            FormatError::UnknownTag { tag } => write!(f, "Unknown constant tag {}", tag),
+            FormatError::InvalidString => write!(f, "String isn't valid UTF-8"),
+            FormatError::InvalidLines => write!(f, "Line table doesn't match the code"),
+            FormatError::TrailingBytes => write!(f, "Unexpected bytes after the script"),
+            FormatError::ScriptArity { arity } => {
+                write!(f, "Script takes {} arguments instead of none", arity)
+            }
+            FormatError::TooDeep => {
+                write!(f, "Functions are nested more than {} deep", MAX_NESTING)
+            }
+            FormatError::UnknownOpcode { offset, byte } => {
+                write!(f, "Unknown opcode {} at offset {}", byte, offset)
+            }
+            FormatError::TruncatedInstruction { offset } => {
+                write!(f, "Truncated instruction at offset {}", offset)
+            }
+            FormatError::ConstantOutOfRange { offset, index } => write!(
+                f,
+                "Constant index {} out of range at offset {}",
+                index, offset
+            ),
+            FormatError::JumpOutOfRange { offset } => {
+                write!(f, "Jump target out of range at offset {}", offset)
+            }
+        }
+    }
+}
+
+/// Whether `bytes` start like a compiled file, rather than Lox source.
+pub fn is_compiled(bytes: &[u8]) -> bool {
+    bytes.starts_with(MAGIC)
+}
+
+/// Encodes a top-level script, and every function nested in it.
+pub fn serialize(script: &ObjFunction) -> Result<Vec<u8>, FormatError> {
+    let mut bytes = MAGIC.to_vec();
+    bytes.extend_from_slice(&BYTECODE_VERSION.to_le_bytes());
+    write_function(&mut bytes, script)?;
+    Ok(bytes)
+}
+
+/// Decodes a script written by `serialize`, interning its strings in
+/// `strings`.
+pub fn deserialize(bytes: &[u8], strings: &mut Interner) -> Result<ObjFunction, FormatError> {
+    let mut reader = Reader {
+        bytes,
+        strings,
+        depth: 0,
+    };
+
+    if reader.take(MAGIC.len())? != MAGIC {
+        return Err(FormatError::NotCompiled);
+    }
+    let version = reader.u32()?;
+    if version != BYTECODE_VERSION {
+        return Err(FormatError::WrongVersion { version });
+    }
+
+    let script = reader.function()?;
+    if !reader.bytes.is_empty() {
+        return Err(FormatError::TrailingBytes);
+    }
+    if script.arity != 0 {
+        return Err(FormatError::ScriptArity {
+            arity: script.arity,
+        });
+    }
+    Ok(script)
+}
+
+fn write_u32(bytes: &mut Vec<u8>, n: usize) {
+    let n = u32::try_from(n).expect("Chunk too large to save");
+    bytes.extend_from_slice(&n.to_le_bytes());
+}
+
+fn write_string(bytes: &mut Vec<u8>, chars: &str) {
+    write_u32(bytes, chars.len());
+    bytes.extend_from_slice(chars.as_bytes());
+}
+
+fn write_function(bytes: &mut Vec<u8>, function: &ObjFunction) -> Result<(), FormatError> {
+    match &function.name {
+        Some(name) => {
+            bytes.push(1);
+            write_string(bytes, &name.chars);
+        }
+        None => bytes.push(0),
+    }
+    bytes.push(function.arity as u8);
+
+    let chunk = &function.chunk;
+    let code = chunk.code();
+    write_u32(bytes, code.len());
+    bytes.extend_from_slice(&code);
+
+    let runs: Vec<(usize, usize)> = chunk.line_runs().collect();
+    write_u32(bytes, runs.len());
+    for (offset, line) in runs {
+        write_u32(bytes, offset);
+        write_u32(bytes, line);
+    }
+
+    write_u32(bytes, chunk.constants.len());
+    for i in 0..chunk.constants.len() {
+        match chunk.constants.at(i) {
+            Value::Nil => bytes.push(TAG_NIL),
+            Value::Bool(false) => bytes.push(TAG_FALSE),
+            Value::Bool(true) => bytes.push(TAG_TRUE),
+            Value::Number(n) => {
+                bytes.push(TAG_NUMBER);
+                bytes.extend_from_slice(&n.to_bits().to_le_bytes());
+            }
+            Value::String(string) => {
+                bytes.push(TAG_STRING);
+                write_string(bytes, &string.chars);
+            }
+            Value::Function(function) => {
+                bytes.push(TAG_FUNCTION);
+                write_function(bytes, &function)?;
+            }
+            value => {
+                return Err(FormatError::Unsupported {
+                    value: value.to_string(),
+                })
+            }
+        }
+    }
+
+    Ok(())
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    strings: &'a mut Interner,
+    /// How many functions enclose the one being read.
+    depth: usize,
+}
+
+impl Reader<'_> {
+    fn take(&mut self, len: usize) -> Result<&[u8], FormatError> {
+        if self.bytes.len() < len {
+            return Err(FormatError::Truncated);
+        }
+        let (taken, rest) = self.bytes.split_at(len);
+        self.bytes = rest;
+        Ok(taken)
+    }
+
+    fn u8(&mut self) -> Result<u8, FormatError> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u32(&mut self) -> Result<u32, FormatError> {
+        let bytes = self.take(4)?;
+        Ok(u32::from_le_bytes(bytes.try_into().unwrap()))
+    }
+
+    fn len(&mut self) -> Result<usize, FormatError> {
+        Ok(self.u32()? as usize)
+    }
+
+    fn string(&mut self) -> Result<&str, FormatError> {
+        let len = self.len()?;
+        std::str::from_utf8(self.take(len)?).map_err(|_| FormatError::InvalidString)
+    }
+
+    fn function(&mut self) -> Result<ObjFunction, FormatError> {
+        if self.depth == MAX_NESTING {
+            return Err(FormatError::TooDeep);
+        }
+
+        let name = match self.u8()? {
+            0 => None,
+            _ => {
+                let name = self.string()?.to_string();
+                Some(self.strings.intern(&name))
+            }
+        };
+        let mut function = ObjFunction::new(name);
+        function.arity = self.u8()? as usize;
+
+        let len = self.len()?;
+        let code = self.take(len)?.to_vec();
+
+        let run_count = self.len()?;
+        let mut runs = Vec::new();
+        for _ in 0..run_count {
+            runs.push((self.len()?, self.len()?));
+        }
+        function.chunk = rebuild_chunk(&code, &runs)?;
+
+        let constant_count = self.len()?;
+        for _ in 0..constant_count {
+            let value = match self.u8()? {
+                TAG_NIL => Value::Nil,
+                TAG_FALSE => Value::Bool(false),
+                TAG_TRUE => Value::Bool(true),
+                TAG_NUMBER => {
+                    let bits = self.take(8)?.try_into().unwrap();
+                    Value::Number(f64::from_bits(u64::from_le_bytes(bits)))
+                }
+                TAG_STRING => {
+                    let chars = self.string()?.to_string();
+                    Value::String(self.strings.intern(&chars))
+                }
+                TAG_FUNCTION => {
+                    self.depth += 1;
+                    let nested = self.function()?;
+                    self.depth -= 1;
+                    Value::Function(Rc::new(nested))
+                }
+                tag => return Err(FormatError::UnknownTag { tag }),
+            };
+            function.chunk.add_constant(value);
+        }
+
+        verify(&function.chunk)?;
+        Ok(function)
+    }
+}
+
+/// Checks that every instruction in `chunk` is a known opcode with all its
+/// operands, that constant operands are in the table, and that jumps land
+/// inside the code. The compiler never writes anything else, but a saved
+/// file can hold anything.
+fn verify(chunk: &Chunk) -> Result<(), FormatError> {
+    let code = chunk.code();
+    let mut offset = 0;
+
+    while offset < code.len() {
+        let byte = code[offset];
+        let op = OpCode::try_from(byte).map_err(|_| FormatError::UnknownOpcode { offset, byte })?;
+        let info = op.info();
+        let end = offset + info.length();
+        if end > code.len() {
+            return Err(FormatError::TruncatedInstruction { offset });
+        }
+
+        let operand = &code[offset + 1..end];
+        match info.operands.first() {
+            Some(OperandKind::Constant) => {
+                let index = operand[0] as usize;
+                if index >= chunk.constants.len() {
+                    return Err(FormatError::ConstantOutOfRange { offset, index });
+                }
+            }
+            Some(OperandKind::Jump) => {
+                let distance = u16::from_be_bytes([operand[0], operand[1]]) as usize;
+                if end + distance > code.len() {
+                    return Err(FormatError::JumpOutOfRange { offset });
+                }
+            }
+            Some(OperandKind::Loop) => {
+                let distance = u16::from_be_bytes([operand[0], operand[1]]) as usize;
+                if distance > end {
+                    return Err(FormatError::JumpOutOfRange { offset });
+                }
+            }
+            Some(OperandKind::Byte) | None => {}
+        }
+
+        offset = end;
+    }
+
+    Ok(())
+}
+
+/// Writes `code` into a new chunk, giving each byte the line its run says.
+/// The runs must start at offset 0 and go strictly forward through the code.
+fn rebuild_chunk(code: &[u8], runs: &[(usize, usize)]) -> Result<Chunk, FormatError> {
+    let mut chunk = Chunk::new();
+
+    match runs.first() {
+        None if code.is_empty() => return Ok(chunk),
+        Some(&(0, _)) => {}
+        _ => return Err(FormatError::InvalidLines),
+    }
+
+    for (i, &(start, line)) in runs.iter().enumerate() {
+        let end = runs.get(i + 1).map_or(code.len(), |&(next, _)| next);
+        if start >= end || end > code.len() {
+            return Err(FormatError::InvalidLines);
+        }
+        for &byte in &code[start..end] {
+            chunk.write(byte, line);
+        }
+    }
+
+    Ok(chunk)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compiler::compile;
+    use crate::debug::disassemble_function;
+    use crate::vm::{InterpretResult, VM};
+    use std::rc::Rc;
+
+    fn compile_script(source: &str, strings: &mut Interner) -> ObjFunction {
+        compile(source, strings, &mut Vec::new())
+            .ok()
+            .unwrap()
+            .function
+    }
+
+    /// A xorshift generator, so the generated programs are the same on
+    /// every run and a failure can be reproduced.
+    struct Rng(u64);
+
+    impl Rng {
+        fn below(&mut self, n: u64) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0 % n
+        }
+    }
+
+    /// A numeric expression over the globals in `vars`.
+    fn gen_number(rng: &mut Rng, vars: &[String], depth: u32) -> String {
+        let choices = if depth == 0 { 2 } else { 5 };
+
+        match rng.below(choices) {
+            0 => rng.below(100).to_string(),
+            1 => vars[rng.below(vars.len() as u64) as usize].clone(),
+            2 => format!("-({})", gen_number(rng, vars, depth - 1)),
+            3 => {
+                let op = ["+", "-", "*", "/"][rng.below(4) as usize];
+                let a = gen_number(rng, vars, depth - 1);
+                let b = gen_number(rng, vars, depth - 1);
+                format!("({} {} {})", a, op, b)
+            }
+            _ => {
+                let a = gen_number(rng, vars, depth - 1);
+                let b = gen_number(rng, vars, depth - 1);
+                format!("add({}, {})", a, b)
+            }
+        }
+    }
+
+    /// A program that runs without errors. `add` is called with numbers in
+    /// loops long enough to quicken it, and with strings, which undoes that.
+    fn gen_program(rng: &mut Rng) -> String {
+        let mut source = String::from("fun add(a, b) { return a + b; }\nvar total = 0;\n");
+        let mut vars = vec!["total".to_string()];
+
+        for _ in 0..rng.below(12) + 1 {
+            let statement = match rng.below(6) {
+                0 => {
+                    let value = gen_number(rng, &vars, 3);
+                    vars.push(format!("v{}", vars.len()));
+                    format!("var {} = {};", vars[vars.len() - 1], value)
+                }
+                1 => format!("print {};", gen_number(rng, &vars, 3)),
+                2 => format!("print add(\"s{}\", \"t\");", rng.below(10)),
+                3 => format!(
+                    "for (var i = 0; i < {}; i = i + 1) total = add(total, {});\nprint total;",
+                    rng.below(40),
+                    gen_number(rng, &vars, 2)
+                ),
+                4 => format!(
+                    "if ({} < {}) print \"lt\"; else print \"ge\";",
+                    gen_number(rng, &vars, 2),
+                    gen_number(rng, &vars, 2)
+                ),
+                _ => format!(
+                    "{{\n  var local = {};\n  print local * 2;\n}}",
+                    gen_number(rng, &vars, 2)
+                ),
+            };
+            source.push_str(&statement);
+            source.push('\n');
+        }
+
+        source
+    }
+
+    fn disassemble(function: &ObjFunction) -> String {
+        let mut output = Vec::new();
+        disassemble_function(function, &mut output);
+        String::from_utf8(output).unwrap()
+    }
+
+    fn run(vm: &mut VM, script: ObjFunction, source: &str) -> String {
+        let mut output = Vec::new();
+        let result = vm.run_function(Rc::new(script), &mut output);
+        assert_eq!(result, InterpretResult::Ok, "{}", source);
+        String::from_utf8(output).unwrap()
+    }
+
+    #[test]
+    fn round_trip_property_test() {
+        let mut rng = Rng(0x2545_f491_4f6c_dd1d);
+        let mut quickened_runs = 0;
+
+        for _ in 0..200 {
+            let source = gen_program(&mut rng);
+            let mut vm = VM::new();
+            let script = match vm.compile(&source, &mut Vec::new()) {
+                Ok(compiled) => Rc::new(compiled.function),
+                Err(_) => panic!("Generated a program that doesn't compile:\n{}", source),
+            };
+
+            let bytes = serialize(&script).unwrap();
+            let loaded = vm.load(&bytes).unwrap();
+            assert_eq!(serialize(&loaded).unwrap(), bytes, "{}", source);
+            assert_eq!(disassemble(&loaded), disassemble(&script), "{}", source);
+
+            let mut output = Vec::new();
+            assert_eq!(
+                vm.run_function(script.clone(), &mut output),
+                InterpretResult::Ok,
+                "{}",
+                source
+            );
+            let expected = String::from_utf8(output).unwrap();
+
+            // Saved after running, so hot adds start out quickened.
+            let saved = serialize(&script).unwrap();
+            if saved != bytes {
+                quickened_runs += 1;
+            }
+            let quickened = vm.load(&saved).unwrap();
+
+            assert_eq!(run(&mut vm, loaded, &source), expected, "{}", source);
+            assert_eq!(run(&mut vm, quickened, &source), expected, "{}", source);
+        }
+
+        // Otherwise the quickened runs checked nothing new.
+        assert!(quickened_runs > 0);
+    }
+
+    #[test]
+    fn round_trip_test() {
+        let mut strings = Interner::new();
+        let source = "fun add(a, b) {\n  return a + b;\n}\nprint add(1.5, 2);\nprint \"done\";";
+        let script = compile_script(source, &mut strings);
+
+        let bytes = serialize(&script).unwrap();
+        assert!(is_compiled(&bytes));
+
+        let loaded = deserialize(&bytes, &mut strings).unwrap();
+        assert_eq!(loaded.name, None);
+        assert_eq!(loaded.chunk.code(), script.chunk.code());
+        assert_eq!(
+            loaded.chunk.line_runs().collect::<Vec<_>>(),
+            script.chunk.line_runs().collect::<Vec<_>>()
+        );
+        assert_eq!(loaded.chunk.constants.len(), script.chunk.constants.len());
+
+        // Strings come back interned, so they're the same objects.
+        assert_eq!(loaded.chunk.constants.at(2), script.chunk.constants.at(2));
+
+        match loaded.chunk.constants.at(1) {
+            Value::Function(add) => {
+                assert_eq!(add.to_string(), "<fn add>");
+                assert_eq!(add.arity, 2);
+                assert_eq!(add.chunk.get_line(0), Some(2));
+            }
+            value => panic!("Expected a function, found {:?}", value),
+        }
+    }
+
+    #[test]
+    fn errors_test() {
+        let mut strings = Interner::new();
+        let bytes = serialize(&compile_script("print 1;", &mut strings)).unwrap();
+
+        assert_eq!(
+            deserialize(b"print 1;", &mut strings).err(),
+            Some(FormatError::NotCompiled)
+        );
+        assert_eq!(
+            deserialize(&bytes[..bytes.len() - 1], &mut strings).err(),
+            Some(FormatError::Truncated)
+        );
+
+        let mut old = bytes.clone();
+        old[4..8].copy_from_slice(&1u32.to_le_bytes());
+        assert_eq!(
+            deserialize(&old, &mut strings).err(),
+            Some(FormatError::WrongVersion { version: 1 })
+        );
+
+        // The arity byte follows the magic, the version and the name flag.
+        let mut arity = bytes.clone();
+        arity[9] = 1;
+        assert_eq!(
+            deserialize(&arity, &mut strings).err(),
+            Some(FormatError::ScriptArity { arity: 1 })
+        );
+
+        let mut longer = bytes.clone();
+        longer.push(0);
+        assert_eq!(
+            deserialize(&longer, &mut strings).err(),
+            Some(FormatError::TrailingBytes)
+        );
+
+        let mut function = ObjFunction::new(None);
+        function.chunk.add_constant(Value::foreign(1));
+        assert_eq!(
+            serialize(&function).err(),
+            Some(FormatError::Unsupported {
+                value: "<foreign>".to_string()
+            })
+        );
+    }
+
+    #[test]
+    fn nesting_limit_test() {
+        // A script whose only constant is a function whose only constant is
+        // a function, and so on, far deeper than anything compiles to.
+        let mut bytes = MAGIC.to_vec();
+        bytes.extend_from_slice(&BYTECODE_VERSION.to_le_bytes());
+        for _ in 0..200_000 {
+            bytes.extend_from_slice(&[0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1, 0, 0, 0, TAG_FUNCTION]);
+        }
+        bytes.extend_from_slice(&[0; 14]);
+
+        assert_eq!(
+            deserialize(&bytes, &mut Interner::new()).err(),
+            Some(FormatError::TooDeep)
+        );
+    }
+
+    #[test]
+    fn verify_test() {
+        fn chunk(code: &[u8], constants: usize) -> Chunk {
+            let mut chunk = Chunk::new();
+            for &byte in code {
+                chunk.write(byte, 1);
+            }
+            for _ in 0..constants {
+                chunk.add_constant(Value::Nil);
+            }
+            chunk
+        }
+
+        let constant = OpCode::Constant as u8;
+        let jump = OpCode::Jump as u8;
+        let lp = OpCode::Loop as u8;
+        let ret = OpCode::Return as u8;
+
+        assert_eq!(verify(&chunk(&[constant, 0, ret], 1)), Ok(()));
+        assert_eq!(verify(&chunk(&[jump, 0, 1, ret], 0)), Ok(()));
+        assert_eq!(verify(&chunk(&[ret, lp, 0, 4], 0)), Ok(()));
+
+        assert_eq!(
+            verify(&chunk(&[ret, 255], 0)),
+            Err(FormatError::UnknownOpcode {
+                offset: 1,
+                byte: 255
+            })
+        );
+        assert_eq!(
+            verify(&chunk(&[ret, constant], 0)),
+            Err(FormatError::TruncatedInstruction { offset: 1 })
+        );
+        assert_eq!(
+            verify(&chunk(&[constant, 5], 1)),
+            Err(FormatError::ConstantOutOfRange {
+                offset: 0,
+                index: 5
+            })
+        );
+        assert_eq!(
+            verify(&chunk(&[jump, 0, 2, ret], 0)),
+            Err(FormatError::JumpOutOfRange { offset: 0 })
+        );
+        assert_eq!(
+            verify(&chunk(&[lp, 0, 4], 0)),
+            Err(FormatError::JumpOutOfRange { offset: 0 })
+        );
+    }
+
+    #[test]
+    fn rebuild_chunk_test() {
+        let chunk = rebuild_chunk(&[1, 2, 3], &[(0, 7), (2, 9)]).unwrap();
+        assert_eq!(chunk.code(), [1, 2, 3]);
+        assert_eq!(chunk.get_line(1), Some(7));
+        assert_eq!(chunk.get_line(2), Some(9));
+
+        assert!(rebuild_chunk(&[], &[]).unwrap().is_empty());
+        assert!(rebuild_chunk(&[1], &[]).is_err());
+        assert!(rebuild_chunk(&[1, 2], &[(1, 1)]).is_err());
+        assert!(rebuild_chunk(&[1, 2], &[(0, 1), (0, 2)]).is_err());
+        assert!(rebuild_chunk(&[1, 2], &[(0, 1), (5, 2)]).is_err());
+    }
+}
@@ -0,0 +1,118 @@
+use std::collections::HashMap;
+use std::ops::Range;
+
+/// An identifier interned at compile time. Two identifiers have the same
+/// symbol exactly when they're spelled the same, so comparing names is an
+/// integer comparison.
+#[derive(Copy, Clone, PartialEq, Eq, Hash, Debug)]
+pub struct Symbol(u32);
+
+impl Symbol {
+    /// The symbol for the empty name, which no identifier in the source can
+    /// have. The compiler uses it for the callee's reserved slot.
+    pub const EMPTY: Symbol = Symbol(0);
+}
+
+/// Every identifier the compiler has seen, along with where each one appears
+/// in the source. Tools can use the spans to rename a name everywhere it's
+/// written.
+pub struct SymbolTable {
+    ids: HashMap<Box<str>, Symbol>,
+    names: Vec<Box<str>>,
+    /// Byte ranges of each symbol's occurrences, indexed by symbol.
+    occurrences: Vec<Vec<Range<usize>>>,
+}
+
+impl SymbolTable {
+    pub fn new() -> Self {
+        let mut table = SymbolTable {
+            ids: HashMap::new(),
+            names: Vec::new(),
+            occurrences: Vec::new(),
+        };
+        table.intern("");
+        table
+    }
+
+    /// Returns the symbol for `name`, creating it if it hasn't been seen
+    /// before.
+    pub fn intern(&mut self, name: &str) -> Symbol {
+        if let Some(&symbol) = self.ids.get(name) {
+            return symbol;
+        }
+
+        let symbol = Symbol(self.names.len() as u32);
+        self.ids.insert(name.into(), symbol);
+        self.names.push(name.into());
+        self.occurrences.push(Vec::new());
+        symbol
+    }
+
+    /// Notes that `symbol` is written at `span` in the source.
+    pub fn record(&mut self, symbol: Symbol, span: Range<usize>) {
+        self.occurrences[symbol.0 as usize].push(span);
+    }
+
+    pub fn lookup(&self, name: &str) -> Option<Symbol> {
+        self.ids.get(name).copied()
+    }
+
+    pub fn name(&self, symbol: Symbol) -> &str {
+        &self.names[symbol.0 as usize]
+    }
+
+    /// Where `symbol` appears in the source, in the order it was compiled.
+    pub fn occurrences(&self, symbol: Symbol) -> &[Range<usize>] {
+        &self.occurrences[symbol.0 as usize]
+    }
+
+    /// The number of symbols, not counting the empty name.
+    pub fn len(&self) -> usize {
+        self.names.len() - 1
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl Default for SymbolTable {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn intern_test() {
+        let mut symbols = SymbolTable::new();
+
+        let a = symbols.intern("a");
+        let b = symbols.intern("b");
+
+        assert_eq!(symbols.intern("a"), a);
+        assert_ne!(a, b);
+        assert_ne!(a, Symbol::EMPTY);
+        assert_eq!(symbols.intern(""), Symbol::EMPTY);
+        assert_eq!(symbols.name(b), "b");
+        assert_eq!(symbols.lookup("b"), Some(b));
+        assert_eq!(symbols.lookup("c"), None);
+        assert_eq!(symbols.len(), 2);
+    }
+
+    #[test]
+    fn occurrences_test() {
+        let mut symbols = SymbolTable::new();
+
+        let a = symbols.intern("a");
+        symbols.record(a, 4..5);
+        symbols.record(a, 10..11);
+
+        assert_eq!(symbols.occurrences(a), [4..5, 10..11]);
+        let b = symbols.intern("b");
+        assert!(symbols.occurrences(b).is_empty());
+    }
+}
@@ -0,0 +1,62 @@
+use crate::value::ObjString;
+use std::collections::HashMap;
+use std::hash::{BuildHasherDefault, Hasher};
+use std::rc::Rc;
+
+/// A hash map keyed by interned strings. Strings carry their hash with them,
+/// so lookups reuse it instead of hashing the characters again.
+pub type Table<V> = HashMap<Rc<ObjString>, V, BuildHasherDefault<PrehashedHasher>>;
+
+/// A hasher for keys that are already hashes. `write_u32` passes the value
+/// straight through; other writes are folded in FNV-style.
+#[derive(Default)]
+pub struct PrehashedHasher {
+    hash: u64,
+}
+
+impl Hasher for PrehashedHasher {
+    fn finish(&self) -> u64 {
+        self.hash
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            self.hash = (self.hash ^ byte as u64).wrapping_mul(0x100000001b3);
+        }
+    }
+
+    fn write_u32(&mut self, hash: u32) {
+        self.hash = hash as u64;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::interner::Interner;
+    use std::hash::BuildHasher;
+
+    #[test]
+    fn prehashed_test() {
+        let mut strings = Interner::new();
+        let name = strings.intern("name");
+
+        let build = BuildHasherDefault::<PrehashedHasher>::default();
+
+        assert_eq!(build.hash_one(&name), name.hash as u64);
+    }
+
+    #[test]
+    fn table_test() {
+        let mut strings = Interner::new();
+        let mut table: Table<f64> = Table::default();
+
+        table.insert(strings.intern("a"), 1.0);
+        table.insert(strings.intern("b"), 2.0);
+        table.insert(strings.intern("a"), 3.0);
+
+        assert_eq!(table.len(), 2);
+        assert_eq!(table.get(&strings.intern("a")), Some(&3.0));
+        assert_eq!(table.get(&strings.intern("c")), None);
+    }
+}
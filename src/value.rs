@@ -1,47 +1,444 @@
-pub type Value = f64;
+use crate::chunk::Chunk;
+use crate::vm::VM;
+use std::any::Any;
+use std::cell::Cell;
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::io::Write;
+use std::rc::Rc;
+
+/// A heap-allocated, immutable Lox string. Strings are interned, so create
+/// them through `Interner::intern` rather than `ObjString::new`.
+#[derive(PartialEq, Eq, Debug)]
+pub struct ObjString {
+    pub chars: Box<str>,
+    /// FNV-1a hash of `chars`, computed once when the string is created.
+    pub hash: u32,
+}
+
+impl ObjString {
+    pub fn new(chars: &str) -> Self {
+        Self::with_hash(chars, hash_string(chars))
+    }
+
+    /// Creates a string whose hash the caller has already computed with
+    /// `hash_string`.
+    pub fn with_hash(chars: &str, hash: u32) -> Self {
+        ObjString {
+            chars: chars.into(),
+            hash,
+        }
+    }
+}
+
+impl Hash for ObjString {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        state.write_u32(self.hash);
+    }
+}
+
+/// The 32-bit FNV-1a hash of `chars`.
+pub fn hash_string(chars: &str) -> u32 {
+    let mut hash: u32 = 2166136261;
+
+    for byte in chars.bytes() {
+        hash ^= byte as u32;
+        hash = hash.wrapping_mul(16777619);
+    }
+
+    hash
+}
+
+/// A compiled Lox function: its bytecode plus what the VM needs to call it.
+#[derive(Default)]
+pub struct ObjFunction {
+    pub arity: usize,
+    pub chunk: Chunk,
+    /// The function's name, or `None` for the top-level script.
+    pub name: Option<Rc<ObjString>>,
+    /// How many times the function has been called, counted while the VM
+    /// has an event sink.
+    calls: Cell<u32>,
+}
+
+impl ObjFunction {
+    pub fn new(name: Option<Rc<ObjString>>) -> Self {
+        ObjFunction {
+            arity: 0,
+            chunk: Chunk::new(),
+            name,
+            calls: Cell::new(0),
+        }
+    }
+
+    /// Adds one to the function's call count and returns the new count,
+    /// which stops at `u32::MAX`.
+    pub fn bump_calls(&self) -> u32 {
+        let calls = self.calls.get().saturating_add(1);
+        self.calls.set(calls);
+        calls
+    }
+}
+
+impl fmt::Display for ObjFunction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.name {
+            Some(name) => write!(f, "<fn {}>", name.chars),
+            None => write!(f, "<script>"),
+        }
+    }
+}
+
+/// An anonymous object built by an object literal: named fields, kept in the
+/// order the literal wrote them.
+#[derive(PartialEq, Debug)]
+pub struct ObjObject {
+    pub fields: Vec<(Rc<ObjString>, Value)>,
+}
+
+impl ObjObject {
+    pub fn get(&self, name: &ObjString) -> Option<&Value> {
+        self.fields
+            .iter()
+            .find(|(key, _)| **key == *name)
+            .map(|(_, value)| value)
+    }
+}
+
+impl fmt::Display for ObjObject {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.fields.is_empty() {
+            return write!(f, "{{}}");
+        }
+
+        write!(f, "{{")?;
+        for (i, (key, value)) in self.fields.iter().enumerate() {
+            if i > 0 {
+                write!(f, ",")?;
+            }
+            // Quote strings so `{a: "1"}` and `{a: 1}` print differently.
+            match value {
+                Value::String(string) => write!(f, " {}: {:?}", key.chars, string.chars)?,
+                value => write!(f, " {}: {}", key.chars, value)?,
+            }
+        }
+        write!(f, " }}")
+    }
+}
+
+/// A function implemented in Rust. It receives the call's arguments and
+/// returns the call's result.
+pub type NativeFn = fn(&[Value]) -> Value;
+
+/// A native that needs the VM, usually to call back into Lox with
+/// `VM::call_sync`. It also gets the writer the running program prints to.
+/// An `Err` becomes a runtime error.
+pub type VmNativeFn = fn(&mut VM, &[Value], &mut dyn Write) -> Result<Value, String>;
+
+/// A native registered by a host program. It may capture state, and the VM
+/// checks its argument count before calling it. An `Err` becomes a runtime
+/// error.
+pub type HostNativeFn = Box<dyn Fn(&[Value]) -> Result<Value, String>>;
+
+pub enum ObjNative {
+    Fn(NativeFn),
+    VmFn(VmNativeFn),
+    Host {
+        arity: usize,
+        function: HostNativeFn,
+    },
+}
+
+#[derive(Clone)]
+pub enum Value {
+    Bool(bool),
+    Nil,
+    Number(f64),
+    String(Rc<ObjString>),
+    Function(Rc<ObjFunction>),
+    Native(Rc<ObjNative>),
+    Object(Rc<ObjObject>),
+    /// An opaque host object owned by the embedding application. Lox code
+    /// can pass it around but not look inside it.
+    Foreign(Rc<dyn Any>),
+}
+
+impl Value {
+    pub fn foreign<T: Any>(value: T) -> Self {
+        Value::Foreign(Rc::new(value))
+    }
+
+    pub fn is_falsey(&self) -> bool {
+        matches!(self, Value::Nil | Value::Bool(false))
+    }
+
+    /// Borrows the host object inside a foreign value, if this is a foreign
+    /// value holding a `T`.
+    pub fn as_foreign<T: Any>(&self) -> Option<&T> {
+        match self {
+            Value::Foreign(value) => value.downcast_ref::<T>(),
+            _ => None,
+        }
+    }
+
+    /// Like `as_foreign`, but returns a shared handle the host can keep after
+    /// the value is gone from the VM.
+    pub fn to_foreign<T: Any>(&self) -> Option<Rc<T>> {
+        match self {
+            Value::Foreign(value) => value.clone().downcast::<T>().ok(),
+            _ => None,
+        }
+    }
+}
+
+impl PartialEq for Value {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (Value::Bool(a), Value::Bool(b)) => a == b,
+            (Value::Nil, Value::Nil) => true,
+            (Value::Number(a), Value::Number(b)) => a == b,
+            (Value::String(a), Value::String(b)) => Rc::ptr_eq(a, b),
+            (Value::Function(a), Value::Function(b)) => Rc::ptr_eq(a, b),
+            (Value::Native(a), Value::Native(b)) => Rc::ptr_eq(a, b),
+            // Objects have no identity of their own, so they're equal when
+            // they have the same fields with equal values, in any order.
+            (Value::Object(a), Value::Object(b)) => {
+                a.fields.len() == b.fields.len()
+                    && a.fields
+                        .iter()
+                        .all(|(key, value)| b.get(key) == Some(value))
+            }
+            (Value::Foreign(a), Value::Foreign(b)) => Rc::ptr_eq(a, b),
+            _ => false,
+        }
+    }
+}
+
+impl fmt::Debug for Value {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Value::Bool(value) => write!(f, "Bool({})", value),
+            Value::Nil => write!(f, "Nil"),
+            Value::Number(value) => write!(f, "Number({})", value),
+            Value::String(value) => write!(f, "String({:?})", value.chars),
+            Value::Function(value) => write!(f, "Function({})", value),
+            Value::Native(_) => write!(f, "Native(..)"),
+            Value::Object(value) => write!(f, "Object({})", value),
+            Value::Foreign(_) => write!(f, "Foreign(..)"),
+        }
+    }
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Value::Bool(value) => write!(f, "{}", value),
+            Value::Nil => write!(f, "nil"),
+            Value::Number(value) => write!(f, "{}", value),
+            Value::String(value) => write!(f, "{}", value.chars),
+            Value::Function(value) => write!(f, "{}", value),
+            Value::Native(_) => write!(f, "<native fn>"),
+            Value::Object(value) => write!(f, "{}", value),
+            Value::Foreign(_) => write!(f, "<foreign>"),
+        }
+    }
+}
+
+/// How a `ValueArray` stores each value: a NaN box with the `boxed-constants`
+/// feature, or the `Value` itself otherwise. Only constant pools use
+/// `ValueArray`; the stack and globals always hold plain `Value`s.
+#[cfg(feature = "boxed-constants")]
+type Slot = crate::nanbox::NanBox;
+#[cfg(not(feature = "boxed-constants"))]
+type Slot = Value;
+
+impl From<&Value> for Value {
+    fn from(value: &Value) -> Self {
+        value.clone()
+    }
+}
 
 #[derive(Default)]
 pub struct ValueArray {
-    values: Vec<Value>,
+    values: Vec<Slot>,
 }
 
 impl ValueArray {
     pub fn write(&mut self, value: Value) {
-        self.values.push(value);
+        self.values.push(Slot::from(value));
     }
 
     pub fn len(&self) -> usize {
         self.values.len()
     }
 
+    pub fn is_empty(&self) -> bool {
+        self.values.is_empty()
+    }
+
     pub fn at(&self, idx: usize) -> Value {
-        *self.values.get(idx).expect("Index out of bounds")
+        Value::from(self.values.get(idx).expect("Index out of bounds"))
+    }
+
+    pub fn get(&self, idx: usize) -> Option<Value> {
+        self.values.get(idx).map(Value::from)
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::interner::Interner;
 
     #[test]
     fn write_test() {
         let mut value_array: ValueArray = Default::default();
 
-        value_array.write(1.2);
-        value_array.write(5.0);
+        value_array.write(Value::Number(1.2));
+        value_array.write(Value::Number(5.0));
 
         assert_eq!(value_array.values.len(), 2);
-        assert_eq!(value_array.values[0], 1.2);
-        assert_eq!(value_array.values[1], 5.0);
+        assert_eq!(value_array.at(0), Value::Number(1.2));
+        assert_eq!(value_array.at(1), Value::Number(5.0));
     }
 
     #[test]
     fn len_test() {
         let mut value_array: ValueArray = Default::default();
 
-        value_array.write(1.2);
-        value_array.write(5.0);
+        value_array.write(Value::Number(1.2));
+        value_array.write(Value::Number(5.0));
 
         assert_eq!(value_array.len(), 2);
     }
+
+    #[test]
+    fn get_test() {
+        let mut value_array: ValueArray = Default::default();
+
+        value_array.write(Value::Number(1.2));
+
+        assert_eq!(value_array.get(0), Some(Value::Number(1.2)));
+        assert_eq!(value_array.get(1), None);
+    }
+
+    #[test]
+    fn is_falsey_test() {
+        assert!(Value::Nil.is_falsey());
+        assert!(Value::Bool(false).is_falsey());
+        assert!(!Value::Bool(true).is_falsey());
+        assert!(!Value::Number(0.0).is_falsey());
+    }
+
+    #[test]
+    fn display_test() {
+        assert_eq!(Value::Bool(true).to_string(), "true");
+        assert_eq!(Value::Nil.to_string(), "nil");
+        assert_eq!(Value::Number(1.5).to_string(), "1.5");
+        assert_eq!(Value::Number(3.0).to_string(), "3");
+        assert_eq!(
+            Value::String(Rc::new(ObjString::new("hi"))).to_string(),
+            "hi"
+        );
+    }
+
+    #[test]
+    fn function_test() {
+        let mut interner = Interner::new();
+
+        let script = Value::Function(Rc::new(ObjFunction::new(None)));
+        let function = Value::Function(Rc::new(ObjFunction::new(Some(interner.intern("add")))));
+
+        assert_eq!(script.to_string(), "<script>");
+        assert_eq!(function.to_string(), "<fn add>");
+        assert_eq!(function, function.clone());
+        assert_ne!(script, function);
+        assert!(!function.is_falsey());
+    }
+
+    #[test]
+    fn native_test() {
+        fn answer(_args: &[Value]) -> Value {
+            Value::Number(42.0)
+        }
+
+        let native = Value::Native(Rc::new(ObjNative::Fn(answer)));
+
+        assert_eq!(native.to_string(), "<native fn>");
+        assert_eq!(native, native.clone());
+        assert_ne!(native, Value::Native(Rc::new(ObjNative::Fn(answer))));
+    }
+
+    #[test]
+    fn string_equality_test() {
+        let mut interner = Interner::new();
+
+        let a = Value::String(interner.intern("lox"));
+        let b = Value::String(interner.intern("lox"));
+        let c = Value::String(interner.intern("clox"));
+
+        assert_eq!(a, b);
+        assert_ne!(a, c);
+        assert!(!Value::String(Rc::new(ObjString::new(""))).is_falsey());
+    }
+
+    #[test]
+    fn object_test() {
+        let mut interner = Interner::new();
+        let x = interner.intern("x");
+        let name = interner.intern("name");
+
+        let object =
+            |fields: Vec<(Rc<ObjString>, Value)>| Value::Object(Rc::new(ObjObject { fields }));
+        let point = object(vec![
+            (x.clone(), Value::Number(1.0)),
+            (name.clone(), Value::String(interner.intern("p"))),
+        ]);
+        let reordered = object(vec![
+            (name.clone(), Value::String(interner.intern("p"))),
+            (x.clone(), Value::Number(1.0)),
+        ]);
+
+        assert_eq!(point.to_string(), "{ x: 1, name: \"p\" }");
+        assert_eq!(object(vec![]).to_string(), "{}");
+        assert_eq!(point, reordered);
+        assert_ne!(point, object(vec![(x.clone(), Value::Number(1.0))]));
+        assert_ne!(
+            object(vec![(x.clone(), Value::Number(1.0))]),
+            object(vec![(x, Value::Number(2.0))])
+        );
+        assert!(!object(vec![]).is_falsey());
+    }
+
+    #[test]
+    fn hash_string_test() {
+        assert_eq!(hash_string(""), 2166136261);
+        assert_eq!(hash_string("a"), 0xe40c292c);
+        assert_eq!(hash_string("foobar"), 0xbf9cf968);
+        assert_eq!(ObjString::new("lox").hash, hash_string("lox"));
+    }
+
+    #[test]
+    fn foreign_test() {
+        let value = Value::foreign(String::from("handle"));
+
+        assert_eq!(value.as_foreign::<String>().unwrap(), "handle");
+        assert!(value.as_foreign::<f64>().is_none());
+        assert!(Value::Nil.as_foreign::<String>().is_none());
+
+        let handle = value.to_foreign::<String>().unwrap();
+        assert_eq!(*handle, "handle");
+        assert_eq!(value.to_string(), "<foreign>");
+    }
+
+    #[test]
+    fn foreign_equality_test() {
+        let value = Value::foreign(1);
+        let same = value.clone();
+        let other = Value::foreign(1);
+
+        assert_eq!(value, same);
+        assert_ne!(value, other);
+        assert!(!value.is_falsey());
+    }
 }
@@ -1,14 +1,79 @@
-#![allow(dead_code)]
-
-use crate::chunk::{Chunk, OpCode};
-use crate::compiler::compile;
+use crate::chunk::OpCode;
+use crate::compiler::{compile_with_options, Compiled, CompilerOptions};
 use crate::debug::disassemble_instruction;
-use crate::value::Value;
-use std::io::Write;
+use crate::diagnostics::Diagnostics;
+use crate::events::{VmEvent, VmEventSink};
+use crate::interner::Interner;
+use crate::serialize::{deserialize, FormatError};
+use crate::table::Table;
+use crate::value::{NativeFn, ObjFunction, ObjNative, ObjObject, ObjString, Value, VmNativeFn};
+use std::fmt;
+use std::io::{self, Write};
+use std::panic::{self, AssertUnwindSafe};
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+/// How many times an OP_ADD has to add two numbers before the VM rewrites
+/// it to OP_ADD_NUMBER.
+const QUICKEN_THRESHOLD: u8 = 16;
+
+const FRAMES_MAX: usize = 64;
+const STACK_MAX: usize = FRAMES_MAX * (u8::MAX as usize + 1);
+/// How many stack slots a new VM starts with. The stack doubles from there
+/// as needed, up to its maximum.
+const STACK_INITIAL: usize = u8::MAX as usize + 1;
+/// How many calls make a function hot, unless the host sets its own
+/// threshold.
+const HOT_CALLS_DEFAULT: u32 = 1000;
+
+/// Unwraps the result of reading an instruction or operand, turning malformed
+/// bytecode into a runtime error instead of a panic.
+macro_rules! try_operand {
+    ($vm:expr, $read:expr) => {
+        match $read {
+            Ok(operand) => operand,
+            Err(error) => return $vm.runtime_error(&error.to_string()),
+        }
+    };
+}
 
-const DEBUG_TRACE: bool = option_env!("DEBUG_TRACE_EXECUTION").is_some();
+/// Something wrong with the bytecode itself, or with the stack it runs on.
+/// The compiler never produces chunks that hit these, apart from overflow,
+/// but a corrupted or hand-built chunk can.
+#[derive(Clone, PartialEq, Debug)]
+pub enum VmError {
+    RanPastEnd,
+    UnknownOpcode(u8),
+    /// The instruction's operands run past the end of the chunk.
+    Truncated(&'static str),
+    StackUnderflow,
+    StackOverflow,
+    ConstantOutOfRange(usize),
+    /// A name operand referred to a constant that isn't a string.
+    ExpectedString(String),
+    LocalSlotOutOfRange(usize),
+    LoopOutOfRange,
+}
 
-const STACK_MAX: usize = 256;
+impl fmt::Display for VmError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            VmError::RanPastEnd => write!(f, "Ran past the end of the chunk."),
+            VmError::UnknownOpcode(byte) => write!(f, "Unknown opcode {}.", byte),
+            VmError::Truncated(name) => write!(f, "Truncated {} instruction.", name),
+            VmError::StackUnderflow => write!(f, "Stack underflow."),
+            VmError::StackOverflow => write!(f, "Stack overflow."),
+            VmError::ConstantOutOfRange(index) => {
+                write!(f, "Constant index {} out of range.", index)
+            }
+            VmError::ExpectedString(found) => {
+                write!(f, "Expected a string constant, found {}.", found)
+            }
+            VmError::LocalSlotOutOfRange(slot) => write!(f, "Local slot {} out of range.", slot),
+            VmError::LoopOutOfRange => write!(f, "Loop target out of range."),
+        }
+    }
+}
 
 #[derive(PartialEq, Debug)]
 pub enum InterpretResult {
@@ -17,20 +82,113 @@ pub enum InterpretResult {
     RuntimeError = 2,
 }
 
+/// Timing and instruction counts for the most recent call to `interpret`.
+/// Scanning happens on demand while compiling, so it is included in
+/// `compile_time`.
+#[derive(Copy, Clone, Default, PartialEq, Debug)]
+pub struct RunMetrics {
+    pub compile_time: Duration,
+    pub execute_time: Duration,
+    pub instructions: u64,
+    /// How many of `instructions` ran in a quickened form, which skips
+    /// checking what types its operands are.
+    pub quickened: u64,
+}
+
+impl fmt::Display for RunMetrics {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "compile:      {:?}", self.compile_time)?;
+        writeln!(f, "execute:      {:?}", self.execute_time)?;
+        writeln!(f, "instructions: {}", self.instructions)?;
+        write!(f, "quickened:    {}", self.quickened)
+    }
+}
+
+/// Whether the VM prints an execution trace, and which instructions it
+/// shows and how.
+#[derive(Clone, PartialEq, Debug)]
+pub struct TraceOptions {
+    /// Print the stack and each instruction before running it.
+    pub enabled: bool,
+    /// Only trace code in functions with these names, where `script` is the
+    /// top level. Empty traces everything.
+    pub only: Vec<String>,
+    /// Don't trace Lox code that natives call back into.
+    pub skip_natives: bool,
+    /// Print one line per instruction instead of a stack line followed by
+    /// the instruction.
+    pub compact: bool,
+    /// In compact mode, how many of the topmost stack slots to show.
+    pub stack_preview: usize,
+}
+
+impl Default for TraceOptions {
+    fn default() -> Self {
+        TraceOptions {
+            enabled: false,
+            only: Vec::new(),
+            skip_natives: false,
+            compact: false,
+            stack_preview: 4,
+        }
+    }
+}
+
+/// An ongoing function call.
+struct CallFrame {
+    function: Rc<ObjFunction>,
+    ip: usize,
+    /// Index of the frame's first stack slot, which holds the function itself.
+    slots: usize,
+}
+
 pub struct VM {
-    chunk: Chunk,
-    ip: u8,
-    stack: [Value; STACK_MAX],
+    frames: Vec<CallFrame>,
+    /// Slots at and above `stack_top` are unused and hold nil.
+    stack: Vec<Value>,
     stack_top: usize,
+    /// The most slots the stack may grow to.
+    stack_max: usize,
+    metrics: RunMetrics,
+    strings: Interner,
+    globals: Table<Value>,
+    /// Every native the host defined, which `reset` restores the globals
+    /// to.
+    natives: Table<Value>,
+    trace: TraceOptions,
+    compiler_options: CompilerOptions,
+    /// Where error messages go while `interpret_capture` runs. Otherwise
+    /// they're printed to stderr.
+    captured_errors: Option<Vec<u8>>,
+    /// How many natives are currently running, so the trace can leave out
+    /// code they call back into.
+    native_depth: usize,
+    /// Set when a run ends in a runtime error or a native panics. A
+    /// poisoned VM won't interpret anything until `recover` is called.
+    poisoned: bool,
+    events: Option<Box<dyn VmEventSink>>,
+    /// How many calls make a function hot, for `VmEvent::FunctionHot`.
+    hot_calls: u32,
 }
 
 impl Default for VM {
     fn default() -> Self {
         VM {
-            chunk: Chunk::default(),
-            ip: 0,
-            stack: [0.0; STACK_MAX],
+            frames: Vec::with_capacity(FRAMES_MAX),
+            stack: vec![Value::Nil; STACK_INITIAL],
             stack_top: 0,
+            stack_max: STACK_MAX,
+            metrics: RunMetrics::default(),
+            strings: Interner::new(),
+            globals: Table::default(),
+            natives: Table::default(),
+            trace: TraceOptions::default(),
+            compiler_options: CompilerOptions::default(),
+            captured_errors: None,
+            native_depth: 0,
+            poisoned: false,
+            events: None,
+            hot_calls: HOT_CALLS_DEFAULT,
         }
     }
 }
@@ -41,12 +199,285 @@ impl VM {
     }
 
     pub fn interpret<W: Write>(&mut self, source: String, writer: &mut W) -> InterpretResult {
-        compile(source, writer);
-        InterpretResult::Ok
+        if !self.ready() {
+            return InterpretResult::RuntimeError;
+        }
+
+        self.metrics = RunMetrics::default();
+        let compile_start = Instant::now();
+        let compiled = self.compile(&source, writer);
+        self.metrics.compile_time = compile_start.elapsed();
+
+        match compiled {
+            Ok(compiled) => self.run_function(Rc::new(compiled.function), writer),
+            Err(_) => InterpretResult::CompileError,
+        }
+    }
+
+    /// Compiles `source` without running it, interning its strings in this
+    /// VM so `run_function` can run the result.
+    pub fn compile<W: Write>(
+        &mut self,
+        source: &str,
+        writer: &mut W,
+    ) -> Result<Compiled, Diagnostics> {
+        self.emit(VmEvent::CompileStarted { source });
+        let start = Instant::now();
+
+        let mut stderr = io::stderr();
+        let errors: &mut dyn Write = match &mut self.captured_errors {
+            Some(captured) => captured,
+            None => &mut stderr,
+        };
+        let compiled = compile_with_options(
+            source,
+            &mut self.strings,
+            writer,
+            errors,
+            self.compiler_options,
+        );
+
+        self.emit(VmEvent::CompileFinished {
+            duration: start.elapsed(),
+            succeeded: compiled.is_ok(),
+        });
+        compiled
+    }
+
+    /// Loads a script saved by `serialize::serialize`, interning its strings
+    /// in this VM so `run_function` can run the result.
+    pub fn load(&mut self, bytes: &[u8]) -> Result<ObjFunction, FormatError> {
+        deserialize(bytes, &mut self.strings)
+    }
+
+    /// Runs an already compiled top-level script, which must have been
+    /// compiled or loaded by this VM. It shares globals with everything else this VM
+    /// runs.
+    pub fn run_function<W: Write>(
+        &mut self,
+        script: Rc<ObjFunction>,
+        writer: &mut W,
+    ) -> InterpretResult {
+        if !self.ready() {
+            return InterpretResult::RuntimeError;
+        }
+
+        self.push(Value::Function(script.clone()));
+        // Only a hand-built script can take arguments.
+        if let Err(message) = self.call(script, 0) {
+            return self.runtime_error(&message);
+        }
+
+        let execute_start = Instant::now();
+        let result = self.run(writer, 0);
+        self.metrics.execute_time = execute_start.elapsed();
+
+        if result == InterpretResult::Ok {
+            // The script's own return value.
+            self.pop();
+        }
+
+        // The writer may be buffered, and a run is the most a script's
+        // output should lag behind.
+        writer.flush().unwrap();
+
+        self.strings.sweep();
+        result
+    }
+
+    /// Like `interpret`, but collects what the script prints and any error
+    /// messages instead of writing them out. Returns the result, then the
+    /// output, then the errors.
+    pub fn interpret_capture(&mut self, source: &str) -> (InterpretResult, String, String) {
+        let outer = self.captured_errors.replace(Vec::new());
+        let mut output = Vec::new();
+
+        let result = self.interpret(source.to_string(), &mut output);
+
+        let errors = std::mem::replace(&mut self.captured_errors, outer).unwrap_or_default();
+        (
+            result,
+            String::from_utf8_lossy(&output).into_owned(),
+            String::from_utf8_lossy(&errors).into_owned(),
+        )
+    }
+
+    /// Whether the VM can start running a new script, reporting why not.
+    fn ready(&mut self) -> bool {
+        if self.poisoned {
+            self.report("The VM is poisoned by an earlier error; call recover() first.");
+            return false;
+        }
+
+        if !self.frames.is_empty() {
+            // A native called back in. Running a new script here would also
+            // run the rest of the caller's frames.
+            self.report("Can't interpret while the VM is already running.");
+            return false;
+        }
+
+        true
+    }
+
+    fn emit(&mut self, event: VmEvent) {
+        if let Some(sink) = &mut self.events {
+            sink.event(&event);
+        }
+    }
+
+    /// Prints a line of an error message.
+    fn report(&mut self, line: &str) {
+        match &mut self.captured_errors {
+            Some(captured) => writeln!(captured, "{}", line).unwrap(),
+            None => eprintln!("{}", line),
+        }
     }
 
-    pub fn _reset_stack(&mut self) {
+    /// Limits the value stack to `slots` slots. Growing past it is a stack
+    /// overflow error.
+    pub fn set_max_stack(&mut self, slots: usize) {
+        self.stack_max = slots.max(1);
+        self.stack.truncate(self.stack_max.max(self.stack_top));
+    }
+
+    pub fn set_trace_options(&mut self, options: TraceOptions) {
+        self.trace = options;
+    }
+
+    /// Sends this VM's events to `sink` from now on, replacing any earlier
+    /// sink.
+    pub fn set_event_sink(&mut self, sink: Box<dyn VmEventSink>) {
+        self.events = Some(sink);
+    }
+
+    /// Makes a function hot once it's been called `calls` times. Only
+    /// matters while there's an event sink.
+    pub fn set_hot_call_threshold(&mut self, calls: u32) {
+        self.hot_calls = calls;
+    }
+
+    /// Options for everything this VM compiles from now on.
+    pub fn set_compiler_options(&mut self, options: CompilerOptions) {
+        self.compiler_options = options;
+    }
+
+    /// Makes a Rust function callable from Lox as the global `name`.
+    pub fn define_native(&mut self, name: &str, function: NativeFn) {
+        self.install_native(name, ObjNative::Fn(function));
+    }
+
+    /// Like `define_native`, for natives that need access to the VM.
+    pub fn define_vm_native(&mut self, name: &str, function: VmNativeFn) {
+        self.install_native(name, ObjNative::VmFn(function));
+    }
+
+    /// Makes `function` callable from Lox as the global `name`, taking exactly
+    /// `arity` arguments. Unlike `define_native`, `function` can be a closure
+    /// over host state, and returning an `Err` reports a runtime error.
+    pub fn register_native<F>(&mut self, name: &str, arity: usize, function: F)
+    where
+        F: Fn(&[Value]) -> Result<Value, String> + 'static,
+    {
+        self.install_native(
+            name,
+            ObjNative::Host {
+                arity,
+                function: Box::new(function),
+            },
+        );
+    }
+
+    fn install_native(&mut self, name: &str, native: ObjNative) {
+        let name = self.strings.intern(name);
+        let native = Value::Native(Rc::new(native));
+        self.natives.insert(name.clone(), native.clone());
+        self.globals.insert(name, native);
+    }
+
+    /// Interns `chars` in this VM, for natives that return new strings.
+    pub fn intern(&mut self, chars: &str) -> Rc<ObjString> {
+        self.strings.intern(chars)
+    }
+
+    /// Calls `callee` with `args` and runs it to completion, for natives that
+    /// call back into Lox.
+    ///
+    /// If the call raises a runtime error, the error has already been
+    /// reported and the VM reset by the time this returns `Err`. The native
+    /// should pass the `Err` straight back. Any `Err` also poisons the VM, so
+    /// a native that carries on anyway ends the run as soon as it returns.
+    pub fn call_sync(
+        &mut self,
+        callee: Value,
+        args: &[Value],
+        writer: &mut dyn Write,
+    ) -> Result<Value, String> {
+        let result = self.try_call_sync(callee, args, writer);
+        if result.is_err() {
+            self.poisoned = true;
+        }
+        result
+    }
+
+    fn try_call_sync(
+        &mut self,
+        callee: Value,
+        args: &[Value],
+        writer: &mut dyn Write,
+    ) -> Result<Value, String> {
+        let base_frames = self.frames.len();
+
+        self.reserve(args.len() + 1)
+            .map_err(|error| error.to_string())?;
+        self.push(callee.clone());
+        for arg in args {
+            self.push(arg.clone());
+        }
+        self.call_value(callee, args.len(), writer)?;
+
+        if self.frames.len() > base_frames && self.run(writer, base_frames) != InterpretResult::Ok {
+            return Err(String::new());
+        }
+
+        Ok(self.pop())
+    }
+
+    pub fn get_global(&mut self, name: &str) -> Option<Value> {
+        let name = self.strings.intern(name);
+        self.globals.get(&name).cloned()
+    }
+
+    pub fn is_poisoned(&self) -> bool {
+        self.poisoned
+    }
+
+    /// Throws away everything left over from an interrupted run, keeping
+    /// globals, and makes the VM usable again.
+    pub fn recover(&mut self) {
+        self.reset_stack();
+        self.native_depth = 0;
+        self.poisoned = false;
+    }
+
+    /// Forgets every global the scripts defined, restoring the natives the
+    /// host defined even if a script replaced them, and makes the VM usable
+    /// again. Options set on the VM are kept.
+    pub fn reset(&mut self) {
+        self.recover();
+        self.globals = self.natives.clone();
+        self.strings.sweep();
+    }
+
+    pub fn last_run_metrics(&self) -> RunMetrics {
+        self.metrics
+    }
+
+    pub fn reset_stack(&mut self) {
+        for slot in &mut self.stack[..self.stack_top] {
+            *slot = Value::Nil;
+        }
         self.stack_top = 0;
+        self.frames.clear();
     }
 
     pub fn push(&mut self, value: Value) {
@@ -56,89 +487,537 @@ impl VM {
 
     pub fn pop(&mut self) -> Value {
         self.stack_top -= 1;
-        *self
+        let slot = self
             .stack
-            .get(self.stack_top)
-            .expect("Stack index out of bounds")
+            .get_mut(self.stack_top)
+            .expect("Stack index out of bounds");
+        std::mem::replace(slot, Value::Nil)
+    }
+
+    fn peek(&self, distance: usize) -> &Value {
+        &self.stack[self.stack_top - 1 - distance]
     }
 
-    fn run<W: Write>(&mut self, writer: &mut W) -> InterpretResult {
-        let mut instruction: u8;
+    fn frame(&self) -> &CallFrame {
+        self.frames.last().expect("No function is running")
+    }
 
-        loop {
-            if DEBUG_TRACE {
-                write!(writer, "          ").unwrap();
-                for i in 0..self.stack_top {
-                    let value = self.stack.get(i).expect("Stack index out of bounds");
-                    write!(writer, "[ {} ]", value).unwrap();
+    fn frame_mut(&mut self) -> &mut CallFrame {
+        self.frames.last_mut().expect("No function is running")
+    }
+
+    fn runtime_error(&mut self, message: &str) -> InterpretResult {
+        let mut lines = vec![message.to_string()];
+
+        for frame in self.frames.iter().rev() {
+            let function = &frame.function;
+            let line = function
+                .chunk
+                .get_line(frame.ip.saturating_sub(1))
+                .unwrap_or(0);
+            lines.push(match &function.name {
+                Some(name) => format!("[line {}] in {}()", line, name.chars),
+                None => format!("[line {}] in script", line),
+            });
+        }
+
+        for line in &lines {
+            self.report(line);
+        }
+        self.emit(VmEvent::RuntimeError {
+            message,
+            trace: &lines[1..],
+        });
+
+        self.reset_stack();
+        self.poisoned = true;
+        InterpretResult::RuntimeError
+    }
+
+    /// Pushes a frame for `function`, whose arguments are the top
+    /// `arg_count` stack values.
+    fn call(&mut self, function: Rc<ObjFunction>, arg_count: usize) -> Result<(), String> {
+        if arg_count != function.arity {
+            return Err(format!(
+                "Expected {} arguments but got {}.",
+                function.arity, arg_count
+            ));
+        }
+
+        if self.frames.len() == FRAMES_MAX {
+            return Err("Stack overflow.".to_string());
+        }
+
+        if self.events.is_some() && function.bump_calls() == self.hot_calls {
+            if let Some(name) = &function.name {
+                self.emit(VmEvent::FunctionHot {
+                    name: &name.chars,
+                    calls: self.hot_calls,
+                });
+            }
+        }
+
+        self.frames.push(CallFrame {
+            function,
+            ip: 0,
+            slots: self.stack_top - arg_count - 1,
+        });
+        Ok(())
+    }
+
+    fn call_value<W: Write + ?Sized>(
+        &mut self,
+        callee: Value,
+        arg_count: usize,
+        writer: &mut W,
+    ) -> Result<(), String> {
+        match callee {
+            Value::Function(function) => self.call(function, arg_count),
+            Value::Native(native) => {
+                if let ObjNative::Host { arity, .. } = &*native {
+                    if arg_count != *arity {
+                        return Err(format!(
+                            "Expected {} arguments but got {}.",
+                            arity, arg_count
+                        ));
+                    }
+                }
+
+                let args = self.stack[self.stack_top - arg_count..self.stack_top].to_vec();
+                let mut writer = writer;
+                self.native_depth += 1;
+                let result = panic::catch_unwind(AssertUnwindSafe(|| match &*native {
+                    ObjNative::Fn(function) => Ok(function(&args)),
+                    ObjNative::VmFn(function) => function(self, &args, &mut writer),
+                    ObjNative::Host { function, .. } => function(&args),
+                }));
+                self.native_depth -= 1;
+
+                let result = match result {
+                    Ok(result) => result?,
+                    Err(_) => {
+                        self.poisoned = true;
+                        return Err("Native function panicked.".to_string());
+                    }
+                };
+                if self.poisoned {
+                    // The native ignored an error from `call_sync`, so the
+                    // stack may not hold what it did before the call.
+                    return Err("Native function ignored an error.".to_string());
+                }
+
+                for _ in 0..=arg_count {
+                    self.pop();
                 }
-                writeln!(writer).unwrap();
+                self.push(result);
+                Ok(())
+            }
+            _ => Err("Can only call functions and classes.".to_string()),
+        }
+    }
+
+    /// Prints the stack and the instruction at `offset`, which is about to
+    /// run, if the trace options let it through. `read_instruction` must
+    /// have checked the instruction first.
+    fn trace_instruction<W: Write + ?Sized>(&self, offset: usize, writer: &mut W) {
+        if self.trace.skip_natives && self.native_depth > 0 {
+            return;
+        }
 
-                disassemble_instruction(&self.chunk, self.ip as usize, writer);
+        let frame = self.frame();
+        if !self.trace.only.is_empty() {
+            let name = frame
+                .function
+                .name
+                .as_ref()
+                .map_or("script", |name| &name.chars);
+            if !self.trace.only.iter().any(|only| only == name) {
+                return;
             }
+        }
+
+        if !self.trace.compact {
+            write!(writer, "          ").unwrap();
+            for value in &self.stack[..self.stack_top] {
+                write!(writer, "[ {} ]", value).unwrap();
+            }
+            writeln!(writer).unwrap();
+
+            disassemble_instruction(&frame.function.chunk, offset, writer);
+            return;
+        }
+
+        let mut instruction = Vec::new();
+        disassemble_instruction(&frame.function.chunk, offset, &mut instruction);
+        let instruction = String::from_utf8(instruction).expect("Disassembly is UTF-8");
+        write!(writer, "{:<40}", instruction.trim_end()).unwrap();
+
+        let shown = self.stack_top.min(self.trace.stack_preview);
+        if shown < self.stack_top {
+            write!(writer, "... ").unwrap();
+        }
+        for value in &self.stack[self.stack_top - shown..self.stack_top] {
+            write!(writer, "[ {} ]", value).unwrap();
+        }
+        writeln!(writer).unwrap();
+    }
 
-            instruction = self.read_byte();
+    /// Runs until the frame count drops back to `base_frames`, leaving the
+    /// last returned value on the stack.
+    fn run<W: Write + ?Sized>(&mut self, writer: &mut W, base_frames: usize) -> InterpretResult {
+        loop {
+            let offset = self.frame().ip;
+            let instruction = try_operand!(self, self.read_instruction());
+            if self.trace.enabled {
+                self.trace_instruction(offset, writer);
+            }
+            self.metrics.instructions += 1;
 
-            match OpCode::try_from(instruction) {
-                Ok(OpCode::Constant) => {
-                    let constant = self.read_constant();
+            match instruction {
+                OpCode::Constant => {
+                    let constant = try_operand!(self, self.read_constant());
                     self.push(constant);
                 }
-                Ok(OpCode::Add) => self.binary_op(|a, b| a + b),
-                Ok(OpCode::Subtract) => self.binary_op(|a, b| a - b),
-                Ok(OpCode::Multiply) => self.binary_op(|a, b| a * b),
-                Ok(OpCode::Divide) => self.binary_op(|a, b| a / b),
-                Ok(OpCode::Negate) => {
-                    let pop = self.pop();
-                    self.push(-pop);
+                OpCode::Nil => self.push(Value::Nil),
+                OpCode::True => self.push(Value::Bool(true)),
+                OpCode::False => self.push(Value::Bool(false)),
+                OpCode::Pop => {
+                    self.pop();
+                }
+                OpCode::GetLocal => {
+                    let slot = try_operand!(self, self.read_slot());
+                    self.push(self.stack[slot].clone());
+                }
+                OpCode::SetLocal => {
+                    let slot = try_operand!(self, self.read_slot());
+                    self.stack[slot] = self.peek(0).clone();
+                }
+                OpCode::GetGlobal => {
+                    let name = try_operand!(self, self.read_string());
+                    match self.globals.get(&name) {
+                        Some(value) => self.push(value.clone()),
+                        None => {
+                            let message = format!("Undefined variable '{}'.", name.chars);
+                            return self.runtime_error(&message);
+                        }
+                    }
+                }
+                OpCode::DefineGlobal => {
+                    let name = try_operand!(self, self.read_string());
+                    let value = self.pop();
+                    self.globals.insert(name, value);
+                }
+                OpCode::SetGlobal => {
+                    let name = try_operand!(self, self.read_string());
+                    let value = self.peek(0).clone();
+                    match self.globals.get_mut(&name) {
+                        Some(slot) => *slot = value,
+                        None => {
+                            let message = format!("Undefined variable '{}'.", name.chars);
+                            return self.runtime_error(&message);
+                        }
+                    }
+                }
+                OpCode::Equal => {
+                    let b = self.pop();
+                    let a = self.pop();
+                    self.push(Value::Bool(a == b));
+                }
+                OpCode::Greater => {
+                    if !self.binary_op(|a, b| Value::Bool(a > b)) {
+                        return self.runtime_error("Operands must be numbers.");
+                    }
+                }
+                OpCode::Less => {
+                    if !self.binary_op(|a, b| Value::Bool(a < b)) {
+                        return self.runtime_error("Operands must be numbers.");
+                    }
                 }
-                Ok(OpCode::Return) => {
+                OpCode::Add => match (self.peek(1), self.peek(0)) {
+                    (Value::String(_), Value::String(_)) => self.concatenate(),
+                    (Value::Number(_), Value::Number(_)) => {
+                        self.binary_op(|a, b| Value::Number(a + b));
+
+                        let frame = self.frame();
+                        let offset = frame.ip - 1;
+                        if frame.function.chunk.bump_feedback(offset) >= QUICKEN_THRESHOLD {
+                            frame.function.chunk.quicken(offset, OpCode::AddNumber);
+                        }
+                    }
+                    _ => {
+                        return self.runtime_error("Operands must be two numbers or two strings.");
+                    }
+                },
+                OpCode::AddNumber => {
+                    let top = self.stack_top;
+                    if let (Value::Number(a), Value::Number(b)) =
+                        (&self.stack[top - 2], &self.stack[top - 1])
+                    {
+                        self.stack[top - 2] = Value::Number(a + b);
+                        self.stack[top - 1] = Value::Nil;
+                        self.stack_top -= 1;
+                        self.metrics.quickened += 1;
+                    } else {
+                        // The guess was wrong. Go back to the general add and
+                        // let it count afresh.
+                        let frame = self.frame_mut();
+                        frame.ip -= 1;
+                        frame.function.chunk.quicken(frame.ip, OpCode::Add);
+                    }
+                }
+                OpCode::Subtract => {
+                    if !self.binary_op(|a, b| Value::Number(a - b)) {
+                        return self.runtime_error("Operands must be numbers.");
+                    }
+                }
+                OpCode::Multiply => {
+                    if !self.binary_op(|a, b| Value::Number(a * b)) {
+                        return self.runtime_error("Operands must be numbers.");
+                    }
+                }
+                OpCode::Divide => {
+                    if !self.binary_op(|a, b| Value::Number(a / b)) {
+                        return self.runtime_error("Operands must be numbers.");
+                    }
+                }
+                OpCode::Not => {
+                    let value = self.pop();
+                    self.push(Value::Bool(value.is_falsey()));
+                }
+                OpCode::Negate => match *self.peek(0) {
+                    Value::Number(value) => {
+                        self.pop();
+                        self.push(Value::Number(-value));
+                    }
+                    _ => return self.runtime_error("Operand must be a number."),
+                },
+                OpCode::Print => {
                     writeln!(writer, "{}", self.pop()).unwrap();
-                    return InterpretResult::Ok;
                 }
-                Err(_) => panic!("Unknown opcode: {}", instruction),
+                OpCode::Jump => {
+                    let offset = try_operand!(self, self.read_short());
+                    self.frame_mut().ip += offset as usize;
+                }
+                OpCode::JumpIfFalse => {
+                    let offset = try_operand!(self, self.read_short());
+                    if self.peek(0).is_falsey() {
+                        self.frame_mut().ip += offset as usize;
+                    }
+                }
+                OpCode::Loop => {
+                    let offset = try_operand!(self, self.read_short()) as usize;
+                    match self.frame().ip.checked_sub(offset) {
+                        Some(ip) => self.frame_mut().ip = ip,
+                        None => return self.runtime_error(&VmError::LoopOutOfRange.to_string()),
+                    }
+                }
+                OpCode::Call => {
+                    let arg_count = try_operand!(self, self.read_byte()) as usize;
+                    if self.stack_top - self.frame().slots - 1 <= arg_count {
+                        return self.runtime_error(&VmError::StackUnderflow.to_string());
+                    }
+                    let callee = self.peek(arg_count).clone();
+                    if let Err(message) = self.call_value(callee, arg_count, writer) {
+                        if self.frames.is_empty() {
+                            // A native's nested call already reported it.
+                            return InterpretResult::RuntimeError;
+                        }
+                        return self.runtime_error(&message);
+                    }
+                }
+                OpCode::Object => {
+                    let field_count = try_operand!(self, self.read_byte()) as usize;
+                    if self.stack_top - self.frame().slots - 1 < field_count * 2 {
+                        return self.runtime_error(&VmError::StackUnderflow.to_string());
+                    }
+
+                    let start = self.stack_top - field_count * 2;
+                    let mut fields = Vec::with_capacity(field_count);
+                    for pair in self.stack[start..self.stack_top].chunks(2) {
+                        match &pair[0] {
+                            Value::String(name) => fields.push((name.clone(), pair[1].clone())),
+                            value => {
+                                let message = format!("Expected a field name, found {}.", value);
+                                return self.runtime_error(&message);
+                            }
+                        }
+                    }
+
+                    while self.stack_top > start {
+                        self.pop();
+                    }
+                    self.push(Value::Object(Rc::new(ObjObject { fields })));
+                }
+                OpCode::GetProperty => {
+                    let name = try_operand!(self, self.read_string());
+                    let object = match self.peek(0) {
+                        Value::Object(object) => object.clone(),
+                        _ => return self.runtime_error("Only objects have properties."),
+                    };
+
+                    match object.get(&name) {
+                        Some(value) => {
+                            let value = value.clone();
+                            self.pop();
+                            self.push(value);
+                        }
+                        None => {
+                            let message = format!("Undefined property '{}'.", name.chars);
+                            return self.runtime_error(&message);
+                        }
+                    }
+                }
+                OpCode::Return => {
+                    let result = self.pop();
+                    let frame = self.frames.pop().expect("No function is running");
+
+                    while self.stack_top > frame.slots {
+                        self.pop();
+                    }
+                    self.push(result);
+
+                    if self.frames.len() == base_frames {
+                        return InterpretResult::Ok;
+                    }
+                }
             }
         }
     }
 
+    /// Applies `op` to the top two stack values if both are numbers. Returns
+    /// false, leaving the stack untouched, if either operand is not a number.
     #[inline]
-    fn binary_op<F>(&mut self, op: F)
+    fn binary_op<F>(&mut self, op: F) -> bool
     where
-        F: Fn(Value, Value) -> Value,
+        F: Fn(f64, f64) -> Value,
     {
+        match (self.peek(1), self.peek(0)) {
+            (&Value::Number(a), &Value::Number(b)) => {
+                self.pop();
+                self.pop();
+                self.push(op(a, b));
+                true
+            }
+            _ => false,
+        }
+    }
+
+    fn concatenate(&mut self) {
         let b = self.pop();
         let a = self.pop();
-        self.push(op(a, b));
+
+        if let (Value::String(a), Value::String(b)) = (a, b) {
+            let chars = [&*a.chars, &*b.chars].concat();
+            let string = self.strings.intern(&chars);
+            self.push(Value::String(string));
+        }
+    }
+
+    /// Reads the next opcode. Checks that the whole instruction is in the
+    /// chunk and that the frame has the stack values it reads, which only a
+    /// corrupted chunk can get wrong.
+    fn read_instruction(&mut self) -> Result<OpCode, VmError> {
+        let frame = self.frame();
+        let chunk = &frame.function.chunk;
+        let byte = chunk.get_byte(frame.ip).ok_or(VmError::RanPastEnd)?;
+        let op = OpCode::try_from(byte).map_err(|_| VmError::UnknownOpcode(byte))?;
+
+        let info = op.info();
+        if frame.ip + info.length() > chunk.len() {
+            return Err(VmError::Truncated(info.name));
+        }
+        if self.stack_top - frame.slots - 1 < info.inputs as usize {
+            return Err(VmError::StackUnderflow);
+        }
+        // No instruction grows the stack by more than one slot.
+        self.reserve(1)?;
+
+        self.frame_mut().ip += 1;
+        Ok(op)
+    }
+
+    /// Makes sure there's room to push `slots` more values, growing the
+    /// stack if it's allowed to.
+    #[inline]
+    fn reserve(&mut self, slots: usize) -> Result<(), VmError> {
+        let needed = self.stack_top + slots;
+        if needed <= self.stack.len() {
+            return Ok(());
+        }
+        if needed > self.stack_max {
+            return Err(VmError::StackOverflow);
+        }
+
+        let size = (self.stack.len() * 2).clamp(needed, self.stack_max);
+        self.stack.resize(size, Value::Nil);
+        Ok(())
+    }
+
+    /// Reads the next byte of code. `read_instruction` has already checked
+    /// that the current instruction's operands are all there, so this only
+    /// fails if a handler reads more than its instruction holds.
+    #[inline]
+    fn read_byte(&mut self) -> Result<u8, VmError> {
+        let frame = self.frame_mut();
+        let byte = frame
+            .function
+            .chunk
+            .get_byte(frame.ip)
+            .ok_or(VmError::RanPastEnd)?;
+        frame.ip += 1;
+        Ok(byte)
     }
 
     #[inline]
-    fn read_byte(&mut self) -> u8 {
-        let byte = *self
+    fn read_short(&mut self) -> Result<u16, VmError> {
+        Ok(u16::from_be_bytes([self.read_byte()?, self.read_byte()?]))
+    }
+
+    /// Reads a constant operand. Compiled chunks only ever refer to
+    /// constants they contain, but a corrupted chunk might not.
+    #[inline]
+    fn read_constant(&mut self) -> Result<Value, VmError> {
+        let index = self.read_byte()? as usize;
+        self.frame()
+            .function
             .chunk
-            .code
-            .get(self.ip as usize)
-            .expect("Index is out of bounds");
-        self.ip += 1;
-        byte
+            .constants
+            .get(index)
+            .ok_or(VmError::ConstantOutOfRange(index))
+    }
+
+    #[inline]
+    fn read_string(&mut self) -> Result<Rc<ObjString>, VmError> {
+        match self.read_constant()? {
+            Value::String(string) => Ok(string),
+            value => Err(VmError::ExpectedString(value.to_string())),
+        }
     }
 
+    /// Reads a local variable's slot and returns its absolute stack index.
+    /// The slot must already be live.
     #[inline]
-    fn read_constant(&mut self) -> Value {
-        let byte = self.read_byte();
-        self.chunk.constants.at(byte as usize)
+    fn read_slot(&mut self) -> Result<usize, VmError> {
+        let slot = self.read_byte()? as usize;
+        let index = self.frame().slots + slot;
+        if index >= self.stack_top {
+            return Err(VmError::LocalSlotOutOfRange(slot));
+        }
+        Ok(index)
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::chunk::Chunk;
+    use std::cell::RefCell;
 
     #[test]
-    #[ignore]
     fn interpret_constant_test() {
         let mut vm = VM::new();
         let mut output = Vec::new();
-        let source = "1.2".to_string();
+        let source = "print 1.2;".to_string();
 
         let result = vm.interpret(source, &mut output);
         assert_eq!(result, InterpretResult::Ok);
@@ -148,11 +1027,10 @@ mod tests {
     }
 
     #[test]
-    #[ignore]
     fn interpret_negation_test() {
         let mut vm = VM::new();
         let mut output = Vec::new();
-        let source = "return -1.2".to_string();
+        let source = "print -1.2;".to_string();
 
         let result = vm.interpret(source, &mut output);
         assert_eq!(result, InterpretResult::Ok);
@@ -162,11 +1040,10 @@ mod tests {
     }
 
     #[test]
-    #[ignore]
     fn interpret_addition_test() {
         let mut vm = VM::new();
         let mut output = Vec::new();
-        let source = "return 1.2 + 2.3".to_string();
+        let source = "print 1.2 + 2.3;".to_string();
 
         let result = vm.interpret(source, &mut output);
         assert_eq!(result, InterpretResult::Ok);
@@ -176,11 +1053,10 @@ mod tests {
     }
 
     #[test]
-    #[ignore]
     fn interpret_subtraction_test() {
         let mut vm = VM::new();
         let mut output = Vec::new();
-        let source = "return 1.5 - 0.3".to_string();
+        let source = "print 1.5 - 0.3;".to_string();
 
         let result = vm.interpret(source, &mut output);
         assert_eq!(result, InterpretResult::Ok);
@@ -190,11 +1066,10 @@ mod tests {
     }
 
     #[test]
-    #[ignore]
     fn interpret_multiplication_test() {
         let mut vm = VM::new();
         let mut output = Vec::new();
-        let source = "return 1.2 * 2.0".to_string();
+        let source = "print 1.2 * 2.0;".to_string();
 
         let result = vm.interpret(source, &mut output);
         assert_eq!(result, InterpretResult::Ok);
@@ -204,11 +1079,10 @@ mod tests {
     }
 
     #[test]
-    #[ignore]
     fn interpret_division_test() {
         let mut vm = VM::new();
         let mut output = Vec::new();
-        let source = "return 2.4 / 2.0".to_string();
+        let source = "print 2.4 / 2.0;".to_string();
 
         let result = vm.interpret(source, &mut output);
         assert_eq!(result, InterpretResult::Ok);
@@ -216,4 +1090,940 @@ mod tests {
         let output_str = String::from_utf8(output).unwrap();
         assert_eq!(output_str, "1.2\n");
     }
+
+    #[test]
+    fn interpret_literals_test() {
+        let cases = [("true", "true\n"), ("false", "false\n"), ("nil", "nil\n")];
+
+        for (source, expected) in cases {
+            let mut vm = VM::new();
+            let mut output = Vec::new();
+
+            let result = vm.interpret(format!("print {};", source), &mut output);
+            assert_eq!(result, InterpretResult::Ok);
+
+            let output_str = String::from_utf8(output).unwrap();
+            assert_eq!(output_str, expected);
+        }
+    }
+
+    #[test]
+    fn interpret_not_test() {
+        let cases = [
+            ("!true", "false\n"),
+            ("!nil", "true\n"),
+            ("!0", "false\n"),
+            ("!!false", "false\n"),
+        ];
+
+        for (source, expected) in cases {
+            let mut vm = VM::new();
+            let mut output = Vec::new();
+
+            let result = vm.interpret(format!("print {};", source), &mut output);
+            assert_eq!(result, InterpretResult::Ok);
+
+            let output_str = String::from_utf8(output).unwrap();
+            assert_eq!(output_str, expected);
+        }
+    }
+
+    #[test]
+    fn interpret_comparison_test() {
+        let cases = [
+            ("1 == 1", "true\n"),
+            ("1 == 2", "false\n"),
+            ("nil == false", "false\n"),
+            ("true != false", "true\n"),
+            ("2 > 1", "true\n"),
+            ("1 >= 1", "true\n"),
+            ("2 < 1", "false\n"),
+            ("1 <= 2", "true\n"),
+            ("!(5 - 4 > 3 * 2 == !nil)", "true\n"),
+        ];
+
+        for (source, expected) in cases {
+            let mut vm = VM::new();
+            let mut output = Vec::new();
+
+            let result = vm.interpret(format!("print {};", source), &mut output);
+            assert_eq!(result, InterpretResult::Ok);
+
+            let output_str = String::from_utf8(output).unwrap();
+            assert_eq!(output_str, expected);
+        }
+    }
+
+    #[test]
+    fn interpret_string_test() {
+        let cases = [
+            ("\"foo\" + \"bar\"", "foobar\n"),
+            ("\"a\" + \"\" + \"b\"", "ab\n"),
+            ("\"lox\" == \"lo\" + \"x\"", "true\n"),
+            ("\"lox\" != \"clox\"", "true\n"),
+            ("!\"\"", "false\n"),
+        ];
+
+        for (source, expected) in cases {
+            let mut vm = VM::new();
+            let mut output = Vec::new();
+
+            let result = vm.interpret(format!("print {};", source), &mut output);
+            assert_eq!(result, InterpretResult::Ok);
+
+            let output_str = String::from_utf8(output).unwrap();
+            assert_eq!(output_str, expected);
+        }
+    }
+
+    #[test]
+    fn interpret_interns_strings_test() {
+        let mut vm = VM::new();
+        let mut output = Vec::new();
+
+        let source = "var s = \"a\" + \"b\"; print s == \"ab\";".to_string();
+        vm.interpret(source, &mut output);
+        assert_eq!(String::from_utf8(output).unwrap(), "true\n");
+
+        // Once the script has finished, only the global's name and value are
+        // still referenced.
+        assert_eq!(vm.strings.len(), 2);
+    }
+
+    #[test]
+    fn interpret_type_error_test() {
+        for source in [
+            "-true",
+            "1 + nil",
+            "false * 2",
+            "nil < 1",
+            "\"a\" + 1",
+            "-\"a\"",
+        ] {
+            let mut vm = VM::new();
+            let mut output = Vec::new();
+
+            let result = vm.interpret(format!("print {};", source), &mut output);
+            assert_eq!(result, InterpretResult::RuntimeError);
+            assert_eq!(vm.stack_top, 0);
+        }
+    }
+
+    #[test]
+    fn interpret_compile_error_test() {
+        let mut vm = VM::new();
+        let mut output = Vec::new();
+
+        let result = vm.interpret("1 +".to_string(), &mut output);
+        assert_eq!(result, InterpretResult::CompileError);
+    }
+
+    #[test]
+    fn last_run_metrics_test() {
+        let mut vm = VM::new();
+        let mut output = Vec::new();
+
+        vm.interpret("1 + 2;".to_string(), &mut output);
+        assert_eq!(vm.last_run_metrics().instructions, 6);
+
+        vm.interpret("1 +".to_string(), &mut output);
+        assert_eq!(vm.last_run_metrics().instructions, 0);
+    }
+
+    #[test]
+    fn interpret_global_test() {
+        let mut vm = VM::new();
+        let mut output = Vec::new();
+
+        let source = "var a = 1; var b; print b; a = a + 2; print a;".to_string();
+        assert_eq!(vm.interpret(source, &mut output), InterpretResult::Ok);
+
+        let source = "print a; b = \"set\"; print b;".to_string();
+        assert_eq!(vm.interpret(source, &mut output), InterpretResult::Ok);
+
+        let output_str = String::from_utf8(output).unwrap();
+        assert_eq!(output_str, "nil\n3\n3\nset\n");
+    }
+
+    #[test]
+    fn interpret_undefined_global_test() {
+        for source in ["print missing;", "missing = 1;"] {
+            let mut vm = VM::new();
+            let mut output = Vec::new();
+
+            let result = vm.interpret(source.to_string(), &mut output);
+            assert_eq!(result, InterpretResult::RuntimeError);
+        }
+    }
+
+    #[test]
+    fn interpret_local_test() {
+        let mut vm = VM::new();
+        let mut output = Vec::new();
+
+        let source = "
+            var a = \"global\";
+            {
+                var a = \"outer\";
+                {
+                    var a = \"inner\";
+                    print a;
+                }
+                print a;
+                a = \"assigned\";
+                print a;
+            }
+            print a;
+        "
+        .to_string();
+
+        let result = vm.interpret(source, &mut output);
+        assert_eq!(result, InterpretResult::Ok);
+        assert_eq!(vm.stack_top, 0);
+
+        let output_str = String::from_utf8(output).unwrap();
+        assert_eq!(output_str, "inner\nouter\nassigned\nglobal\n");
+    }
+
+    #[test]
+    fn interpret_if_test() {
+        let mut vm = VM::new();
+        let mut output = Vec::new();
+
+        let source = "
+            if (true) print \"then\"; else print \"else\";
+            if (nil) print \"then\"; else print \"else\";
+            if (0) print \"zero is truthy\";
+            if (false) print \"skipped\";
+            var a = 1;
+            if (a == 1) { var b = 2; print a + b; }
+            print a;
+        "
+        .to_string();
+
+        let result = vm.interpret(source, &mut output);
+        assert_eq!(result, InterpretResult::Ok);
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "then\nelse\nzero is truthy\n3\n1\n"
+        );
+    }
+
+    #[test]
+    fn interpret_loops_test() {
+        let mut vm = VM::new();
+        let mut output = Vec::new();
+
+        let source = "
+            var i = 0;
+            while (i < 3) { print i; i = i + 1; }
+            for (var i = 10; i < 13; i = i + 1) print i;
+            print i;
+            for (; i > 0;) i = i - 1;
+            print i;
+        "
+        .to_string();
+
+        let result = vm.interpret(source, &mut output);
+        assert_eq!(result, InterpretResult::Ok);
+        assert_eq!(vm.stack_top, 0);
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "0\n1\n2\n10\n11\n12\n3\n0\n"
+        );
+    }
+
+    #[test]
+    fn interpret_number_format_test() {
+        let cases = [
+            ("1", "1"),
+            ("1.0", "1"),
+            ("1.50", "1.5"),
+            ("007", "7"),
+            ("-0", "-0"),
+            ("0.1 + 0.2", "0.30000000000000004"),
+            ("1 / 3", "0.3333333333333333"),
+            ("123456789012345678901234", "123456789012345690000000"),
+            ("0.000001", "0.000001"),
+            ("1 / 0", "inf"),
+            ("-1 / 0", "-inf"),
+            ("0 / 0", "NaN"),
+        ];
+
+        for (expression, expected) in cases {
+            let mut vm = VM::new();
+            let mut output = Vec::new();
+
+            let result = vm.interpret(format!("print {};", expression), &mut output);
+            assert_eq!(result, InterpretResult::Ok);
+            assert_eq!(
+                String::from_utf8(output).unwrap(),
+                format!("{}\n", expected)
+            );
+        }
+
+        for source in ["print .5;", "print 5.;"] {
+            let mut vm = VM::new();
+            let mut output = Vec::new();
+
+            let result = vm.interpret(source.to_string(), &mut output);
+            assert_eq!(result, InterpretResult::CompileError);
+        }
+    }
+
+    #[test]
+    fn interpret_function_test() {
+        let mut vm = VM::new();
+        let mut output = Vec::new();
+
+        let source = "
+            fun fib(n) {
+                if (n < 2) return n;
+                return fib(n - 2) + fib(n - 1);
+            }
+            fun greet(name) { print \"hi \" + name; }
+            print fib(10);
+            print greet(\"lox\");
+            print fib;
+            {
+                var local = 3;
+                fun twice(x) { return x * 2; }
+                print twice(local);
+            }
+        "
+        .to_string();
+
+        let result = vm.interpret(source, &mut output);
+        assert_eq!(result, InterpretResult::Ok);
+        assert_eq!(vm.stack_top, 0);
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "55\nhi lox\nnil\n<fn fib>\n6\n"
+        );
+    }
+
+    #[test]
+    fn interpret_call_errors_test() {
+        for source in [
+            "fun f(a) {} f();",
+            "fun f() {} f(1, 2);",
+            "var x = 1; x();",
+            "\"str\"();",
+            "fun f() { f(); } f();",
+        ] {
+            let mut vm = VM::new();
+            let mut output = Vec::new();
+
+            let result = vm.interpret(source.to_string(), &mut output);
+            assert_eq!(result, InterpretResult::RuntimeError, "source: {}", source);
+            assert_eq!(vm.stack_top, 0);
+            assert!(vm.frames.is_empty());
+        }
+    }
+
+    #[test]
+    fn interpret_object_test() {
+        let mut vm = VM::new();
+        let mut output = Vec::new();
+
+        let source = "
+            var point = { x: 1, y: { z: \"deep\" } };
+            print point;
+            print point.y.z;
+            print {};
+            print { a: 1, b: 2 } == { b: 2, a: 1 };
+            print { a: 1 } == { a: \"1\" };
+            print point == point;
+        "
+        .to_string();
+
+        let result = vm.interpret(source, &mut output);
+        assert_eq!(result, InterpretResult::Ok);
+        assert_eq!(vm.stack_top, 0);
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "{ x: 1, y: { z: \"deep\" } }\ndeep\n{}\ntrue\nfalse\ntrue\n"
+        );
+    }
+
+    #[test]
+    fn interpret_property_errors_test() {
+        for source in [
+            "print {}.x;",
+            "var a = 1; print a.x;",
+            "print \"s\".length;",
+        ] {
+            let mut vm = VM::new();
+            let mut output = Vec::new();
+
+            let result = vm.interpret(source.to_string(), &mut output);
+            assert_eq!(result, InterpretResult::RuntimeError, "source: {}", source);
+            assert_eq!(vm.stack_top, 0);
+        }
+    }
+
+    #[test]
+    fn interpret_capture_test() {
+        let mut vm = VM::new();
+
+        let (result, output, errors) = vm.interpret_capture("print 1; print \"two\";");
+        assert_eq!(result, InterpretResult::Ok);
+        assert_eq!(output, "1\ntwo\n");
+        assert_eq!(errors, "");
+
+        let (result, output, errors) = vm.interpret_capture("print 1 +;");
+        assert_eq!(result, InterpretResult::CompileError);
+        assert_eq!(output, "");
+        assert_eq!(errors, "[line 1] Error at ';': Expect expression.\n");
+
+        let source = "fun f() {\n  print \"in f\";\n  return -nil;\n}\nf();";
+        let (result, output, errors) = vm.interpret_capture(source);
+        assert_eq!(result, InterpretResult::RuntimeError);
+        assert_eq!(output, "in f\n");
+        assert_eq!(
+            errors,
+            "Operand must be a number.\n[line 3] in f()\n[line 5] in script\n"
+        );
+
+        let (result, _, errors) = vm.interpret_capture("print 1;");
+        assert_eq!(result, InterpretResult::RuntimeError);
+        assert!(errors.starts_with("The VM is poisoned"));
+        assert!(vm.captured_errors.is_none());
+    }
+
+    #[test]
+    fn interpret_native_test() {
+        fn sum(args: &[Value]) -> Value {
+            let total = args.iter().fold(0.0, |total, arg| match arg {
+                Value::Number(n) => total + n,
+                _ => total,
+            });
+            Value::Number(total)
+        }
+
+        let mut vm = VM::new();
+        let mut output = Vec::new();
+        vm.define_native("sum", sum);
+
+        let source = "print sum(); print sum(1, 2, 3) * 2; print sum;".to_string();
+        let result = vm.interpret(source, &mut output);
+
+        assert_eq!(result, InterpretResult::Ok);
+        assert_eq!(vm.stack_top, 0);
+        assert_eq!(String::from_utf8(output).unwrap(), "0\n12\n<native fn>\n");
+    }
+
+    #[test]
+    fn add_quickening_test() {
+        let mut vm = VM::new();
+        let mut output = Vec::new();
+
+        let source = "fun add(a, b) { return a + b; }
+            var total = 0;
+            for (var i = 0; i < 20; i = i + 1) total = add(total, i);
+            print total;
+            print add(\"a\", \"b\");
+            print add(1, 2);"
+            .to_string();
+        assert_eq!(vm.interpret(source, &mut output), InterpretResult::Ok);
+        assert_eq!(String::from_utf8(output).unwrap(), "190\nab\n3\n");
+        // The last four adds in `add` and in the loop increment.
+        assert_eq!(vm.last_run_metrics().quickened, 8);
+
+        let add = match vm.get_global("add") {
+            Some(Value::Function(add)) => add,
+            _ => panic!("add isn't a function"),
+        };
+        // De-quickened by the strings, then one more numeric add.
+        assert!(add.chunk.code().contains(&(OpCode::Add as u8)));
+        assert_eq!(add.chunk.bump_feedback(4), 2);
+        // Quickened adds clear the slot they pop, like every other pop.
+        assert!(vm.stack.iter().all(|value| matches!(value, Value::Nil)));
+    }
+
+    #[test]
+    fn stack_growth_test() {
+        let mut vm = VM::new();
+        let mut output = Vec::new();
+
+        let depth = STACK_INITIAL + 100;
+        let source = format!(
+            "{{ var a = 1; print {}a{}; }}",
+            "a + (".repeat(depth),
+            ")".repeat(depth)
+        );
+        assert_eq!(vm.interpret(source, &mut output), InterpretResult::Ok);
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            format!("{}\n", depth + 1)
+        );
+        assert!(vm.stack.len() > STACK_INITIAL);
+        assert_eq!(vm.stack_top, 0);
+    }
+
+    #[test]
+    fn stack_overflow_test() {
+        let mut vm = VM::new();
+        let mut output = Vec::new();
+        vm.set_max_stack(8);
+
+        let source = "print 1 + (2 + (3 + (4 + (5 + (6 + (7 + (8 + 9)))))));".to_string();
+        assert_eq!(
+            vm.interpret(source, &mut output),
+            InterpretResult::RuntimeError
+        );
+        assert!(vm.stack.len() <= 8);
+
+        vm.recover();
+        let source = "print 1 + (2 + 3);".to_string();
+        assert_eq!(vm.interpret(source, &mut output), InterpretResult::Ok);
+        assert_eq!(String::from_utf8(output).unwrap(), "6\n");
+    }
+
+    #[test]
+    fn poison_and_recover_test() {
+        let mut vm = VM::new();
+        let mut output = Vec::new();
+
+        let source = "var a = 1; print a; -\"oops\";".to_string();
+        assert_eq!(
+            vm.interpret(source, &mut output),
+            InterpretResult::RuntimeError
+        );
+        assert!(vm.is_poisoned());
+
+        let source = "print a;".to_string();
+        assert_eq!(
+            vm.interpret(source.clone(), &mut output),
+            InterpretResult::RuntimeError
+        );
+
+        vm.recover();
+        assert!(!vm.is_poisoned());
+        assert_eq!(vm.interpret(source, &mut output), InterpretResult::Ok);
+        assert_eq!(String::from_utf8(output).unwrap(), "1\n1\n");
+    }
+
+    #[test]
+    fn redefine_function_test() {
+        let mut vm = VM::new();
+        vm.interpret_capture("fun f() { print 1; }");
+        let old = match vm.get_global("f") {
+            Some(Value::Function(function)) => Rc::downgrade(&function),
+            other => panic!("Expected a function, found {:?}", other),
+        };
+
+        let (_, output, _) = vm.interpret_capture("fun f() { print 2; } f();");
+        assert_eq!(output, "2\n");
+        // Nothing else held the old version, so its chunk is gone.
+        assert!(old.upgrade().is_none());
+    }
+
+    #[test]
+    fn reset_test() {
+        fn answer(_args: &[Value]) -> Value {
+            Value::Number(42.0)
+        }
+
+        let mut vm = VM::new();
+        vm.define_native("answer", answer);
+        vm.interpret_capture("var a = 1; print -nil;");
+        assert!(vm.is_poisoned());
+
+        vm.reset();
+        assert!(!vm.is_poisoned());
+        assert_eq!(vm.get_global("a"), None);
+        assert_eq!(vm.interpret_capture("print answer();").1, "42\n");
+
+        // Globals holding natives are still the script's, and shadowed
+        // natives come back.
+        vm.interpret_capture("var alias = answer; answer = 1;");
+        vm.reset();
+        assert_eq!(vm.get_global("alias"), None);
+        assert_eq!(vm.interpret_capture("print answer();").1, "42\n");
+    }
+
+    #[test]
+    fn register_native_test() {
+        let calls = Rc::new(std::cell::Cell::new(0));
+        let counted = calls.clone();
+
+        let mut vm = VM::new();
+        vm.register_native("add", 2, move |args| {
+            counted.set(counted.get() + 1);
+            match args {
+                [Value::Number(a), Value::Number(b)] => Ok(Value::Number(a + b)),
+                _ => Err("add() expects two numbers.".to_string()),
+            }
+        });
+
+        let (result, output, _) = vm.interpret_capture("print add(1, 2); print add(3, 4);");
+        assert_eq!(result, InterpretResult::Ok);
+        assert_eq!(output, "3\n7\n");
+        assert_eq!(calls.get(), 2);
+
+        let (result, _, errors) = vm.interpret_capture("add(1);");
+        assert_eq!(result, InterpretResult::RuntimeError);
+        assert!(errors.starts_with("Expected 2 arguments but got 1.\n"));
+        assert_eq!(calls.get(), 2);
+
+        vm.recover();
+        let (result, _, errors) = vm.interpret_capture("add(1, \"a\");");
+        assert_eq!(result, InterpretResult::RuntimeError);
+        assert!(errors.starts_with("add() expects two numbers.\n"));
+    }
+
+    #[test]
+    fn event_sink_test() {
+        struct Recorder(Rc<RefCell<Vec<String>>>);
+
+        impl VmEventSink for Recorder {
+            fn event(&mut self, event: &VmEvent) {
+                let line = match event {
+                    VmEvent::CompileStarted { source } => format!("compile {}", source),
+                    VmEvent::CompileFinished { succeeded, .. } => format!("compiled {}", succeeded),
+                    VmEvent::RuntimeError { message, trace } => {
+                        format!("error {} {}", message, trace.join(" "))
+                    }
+                    VmEvent::FunctionHot { name, calls } => format!("hot {} {}", name, calls),
+                };
+                self.0.borrow_mut().push(line);
+            }
+        }
+
+        let events = Rc::new(RefCell::new(Vec::new()));
+        let mut vm = VM::new();
+        vm.set_event_sink(Box::new(Recorder(events.clone())));
+        vm.set_hot_call_threshold(3);
+
+        let source = "fun f() {} for (var i = 0; i < 5; i = i + 1) f(); -nil;";
+        vm.interpret_capture(source);
+        vm.recover();
+        vm.interpret_capture("print;");
+
+        assert_eq!(
+            *events.borrow(),
+            vec![
+                format!("compile {}", source),
+                "compiled true".to_string(),
+                "hot f 3".to_string(),
+                "error Operand must be a number. [line 1] in script".to_string(),
+                "compile print;".to_string(),
+                "compiled false".to_string(),
+            ]
+        );
+    }
+
+    #[test]
+    fn native_panic_test() {
+        fn boom(_args: &[Value]) -> Value {
+            panic!("boom");
+        }
+
+        let mut vm = VM::new();
+        let mut output = Vec::new();
+        vm.define_native("boom", boom);
+
+        let source = "fun f() { return boom(); } f();".to_string();
+        assert_eq!(
+            vm.interpret(source, &mut output),
+            InterpretResult::RuntimeError
+        );
+        assert!(vm.is_poisoned());
+        assert_eq!(vm.native_depth, 0);
+
+        vm.recover();
+        assert_eq!(vm.stack_top, 0);
+        assert!(vm.frames.is_empty());
+    }
+
+    #[test]
+    fn reentrant_interpret_test() {
+        fn nested(vm: &mut VM, _args: &[Value], writer: &mut dyn Write) -> Result<Value, String> {
+            let mut writer = writer;
+            let result = vm.interpret("print 1;".to_string(), &mut writer);
+            Ok(Value::Bool(result == InterpretResult::Ok))
+        }
+
+        let mut vm = VM::new();
+        let mut output = Vec::new();
+        vm.define_vm_native("nested", nested);
+
+        let source = "print nested(); print 2;".to_string();
+        assert_eq!(vm.interpret(source, &mut output), InterpretResult::Ok);
+        assert_eq!(String::from_utf8(output).unwrap(), "false\n2\n");
+    }
+
+    #[test]
+    fn run_function_with_arity_test() {
+        let mut script = ObjFunction::new(None);
+        script.arity = 1;
+        script.chunk.write(OpCode::Nil as u8, 1);
+        script.chunk.write(OpCode::Return as u8, 1);
+
+        let mut vm = VM::new();
+        vm.captured_errors = Some(Vec::new());
+        let mut output = Vec::new();
+        assert_eq!(
+            vm.run_function(Rc::new(script), &mut output),
+            InterpretResult::RuntimeError
+        );
+        assert_eq!(
+            String::from_utf8(vm.captured_errors.take().unwrap()).unwrap(),
+            "Expected 1 arguments but got 0.\n"
+        );
+    }
+
+    #[test]
+    fn swallowed_call_sync_error_test() {
+        fn ignore(vm: &mut VM, args: &[Value], writer: &mut dyn Write) -> Result<Value, String> {
+            let _ = vm.call_sync(args[0].clone(), &[], writer);
+            Ok(Value::Nil)
+        }
+
+        let mut vm = VM::new();
+        vm.define_vm_native("ignore", ignore);
+
+        // The error was reported and the stack reset inside the call.
+        let (result, output, errors) =
+            vm.interpret_capture("fun f() { return -nil; } print ignore(f); print 1;");
+        assert_eq!(result, InterpretResult::RuntimeError);
+        assert_eq!(output, "");
+        assert!(errors.starts_with("Operand must be a number.\n"));
+        assert!(vm.is_poisoned());
+
+        // The call never started, so the stack still holds its arguments.
+        vm.recover();
+        let (result, _, errors) = vm.interpret_capture("fun g(a) {} ignore(g); print 1;");
+        assert_eq!(result, InterpretResult::RuntimeError);
+        assert!(errors.starts_with("Native function ignored an error.\n"));
+    }
+
+    /// A VM about to run `chunk` as its script.
+    fn load_chunk(chunk: Chunk) -> VM {
+        let mut vm = VM::new();
+
+        let mut script = ObjFunction::new(None);
+        script.chunk = chunk;
+        let script = Rc::new(script);
+        vm.push(Value::Function(script.clone()));
+        vm.call(script, 0).unwrap();
+        vm
+    }
+
+    fn run_chunk(chunk: Chunk) -> InterpretResult {
+        let mut output = Vec::new();
+        load_chunk(chunk).run(&mut output, 0)
+    }
+
+    fn trace_script(vm: &mut VM) -> String {
+        let mut chunk = Chunk::new();
+        chunk.write(OpCode::Add as u8, 7);
+
+        let mut script = ObjFunction::new(None);
+        script.chunk = chunk;
+        let script = Rc::new(script);
+        vm.push(Value::Function(script.clone()));
+        vm.call(script, 0).unwrap();
+        for i in 1..=3 {
+            vm.push(Value::Number(i as f64));
+        }
+
+        let mut output = Vec::new();
+        vm.trace_instruction(0, &mut output);
+        String::from_utf8(output).unwrap()
+    }
+
+    #[test]
+    fn trace_instruction_test() {
+        let mut vm = VM::new();
+        assert_eq!(
+            trace_script(&mut vm),
+            "          [ <script> ][ 1 ][ 2 ][ 3 ]\n0000  7 OP_ADD\n"
+        );
+
+        let mut vm = VM::new();
+        vm.set_trace_options(TraceOptions {
+            compact: true,
+            stack_preview: 2,
+            ..Default::default()
+        });
+        assert_eq!(
+            trace_script(&mut vm),
+            format!("{:<40}... [ 2 ][ 3 ]\n", "0000  7 OP_ADD")
+        );
+    }
+
+    #[test]
+    fn trace_enabled_test() {
+        let mut vm = VM::new();
+        let mut output = Vec::new();
+        vm.interpret("print 1;".to_string(), &mut output);
+        assert_eq!(String::from_utf8(output).unwrap(), "1\n");
+
+        vm.set_trace_options(TraceOptions {
+            enabled: true,
+            compact: true,
+            ..Default::default()
+        });
+        let mut output = Vec::new();
+        vm.interpret("print 1;".to_string(), &mut output);
+        let output = String::from_utf8(output).unwrap();
+        assert!(output.contains("OP_CONSTANT"), "{}", output);
+        assert!(output.contains("OP_PRINT"), "{}", output);
+    }
+
+    #[test]
+    fn trace_filter_test() {
+        let mut vm = VM::new();
+        vm.set_trace_options(TraceOptions {
+            only: vec!["fib".to_string()],
+            ..Default::default()
+        });
+        assert_eq!(trace_script(&mut vm), "");
+
+        let mut vm = VM::new();
+        vm.set_trace_options(TraceOptions {
+            skip_natives: true,
+            ..Default::default()
+        });
+        vm.native_depth = 1;
+        assert_eq!(trace_script(&mut vm), "");
+    }
+
+    #[test]
+    fn run_unknown_opcode_test() {
+        let mut chunk = Chunk::new();
+        chunk.write(255, 1);
+
+        assert_eq!(run_chunk(chunk), InterpretResult::RuntimeError);
+    }
+
+    #[test]
+    fn run_stack_underflow_test() {
+        let mut chunk = Chunk::new();
+        chunk.write(OpCode::Nil as u8, 1);
+        chunk.write(OpCode::Add as u8, 1);
+        assert_eq!(run_chunk(chunk), InterpretResult::RuntimeError);
+
+        let mut chunk = Chunk::new();
+        chunk.write(OpCode::Call as u8, 1);
+        chunk.write(3, 1);
+        assert_eq!(run_chunk(chunk), InterpretResult::RuntimeError);
+    }
+
+    #[test]
+    fn run_past_end_test() {
+        let mut chunk = Chunk::new();
+        chunk.write(OpCode::Nil as u8, 1);
+        assert_eq!(run_chunk(chunk), InterpretResult::RuntimeError);
+
+        let mut chunk = Chunk::new();
+        chunk.write(OpCode::Constant as u8, 1);
+        assert_eq!(run_chunk(chunk), InterpretResult::RuntimeError);
+
+        let mut chunk = Chunk::new();
+        chunk.write(OpCode::Loop as u8, 1);
+        chunk.write(0, 1);
+        chunk.write(9, 1);
+        assert_eq!(run_chunk(chunk), InterpretResult::RuntimeError);
+
+        assert_eq!(run_chunk(Chunk::new()), InterpretResult::RuntimeError);
+    }
+
+    #[test]
+    fn run_bad_constant_test() {
+        let mut chunk = Chunk::new();
+        chunk.write(OpCode::Constant as u8, 1);
+        chunk.write(0, 1);
+        chunk.write(OpCode::Return as u8, 1);
+
+        assert_eq!(run_chunk(chunk), InterpretResult::RuntimeError);
+    }
+
+    #[test]
+    fn run_bad_global_name_test() {
+        let mut chunk = Chunk::new();
+        let constant = chunk.add_constant(Value::Number(1.0));
+        chunk.write(OpCode::GetGlobal as u8, 1);
+        chunk.write(constant as u8, 1);
+        chunk.write(OpCode::Return as u8, 1);
+
+        assert_eq!(run_chunk(chunk), InterpretResult::RuntimeError);
+    }
+
+    #[test]
+    fn read_errors_test() {
+        let mut chunk = Chunk::new();
+        chunk.write(OpCode::Constant as u8, 1);
+        let mut vm = load_chunk(chunk);
+        assert_eq!(
+            vm.read_instruction().err(),
+            Some(VmError::Truncated("OP_CONSTANT"))
+        );
+
+        let mut chunk = Chunk::new();
+        chunk.write(OpCode::GetGlobal as u8, 1);
+        chunk.write(0, 1);
+        let mut vm = load_chunk(chunk);
+        vm.read_instruction().unwrap();
+        assert_eq!(vm.read_string(), Err(VmError::ConstantOutOfRange(0)));
+        assert_eq!(vm.read_byte(), Err(VmError::RanPastEnd));
+
+        let mut vm = load_chunk(Chunk::new());
+        assert_eq!(vm.read_instruction().err(), Some(VmError::RanPastEnd));
+        vm.stack_max = vm.stack.len();
+        assert_eq!(vm.reserve(vm.stack.len()), Err(VmError::StackOverflow));
+
+        assert_eq!(
+            VmError::ExpectedString("1".to_string()).to_string(),
+            "Expected a string constant, found 1."
+        );
+    }
+
+    #[test]
+    fn trace_corrupted_chunk_test() {
+        let mut chunk = Chunk::new();
+        chunk.write(OpCode::Constant as u8, 1);
+        chunk.write(5, 1);
+        let mut vm = load_chunk(chunk);
+        vm.set_trace_options(TraceOptions {
+            enabled: true,
+            ..Default::default()
+        });
+        let mut output = Vec::new();
+        assert_eq!(vm.run(&mut output, 0), InterpretResult::RuntimeError);
+
+        let output = String::from_utf8(output).unwrap();
+        assert!(
+            output.ends_with("OP_CONSTANT         5 <bad constant 5>\n"),
+            "{}",
+            output
+        );
+
+        // A truncated instruction is reported before it's traced.
+        let mut chunk = Chunk::new();
+        chunk.write(OpCode::Constant as u8, 1);
+        let mut vm = load_chunk(chunk);
+        vm.set_trace_options(TraceOptions {
+            enabled: true,
+            ..Default::default()
+        });
+        let mut output = Vec::new();
+        assert_eq!(vm.run(&mut output, 0), InterpretResult::RuntimeError);
+        assert!(output.is_empty());
+    }
+
+    #[test]
+    fn run_bad_local_slot_test() {
+        for op in [OpCode::GetLocal, OpCode::SetLocal] {
+            let mut chunk = Chunk::new();
+            chunk.write(OpCode::Nil as u8, 1);
+            chunk.write(op as u8, 1);
+            chunk.write(2, 1);
+            chunk.write(OpCode::Return as u8, 1);
+
+            assert_eq!(run_chunk(chunk), InterpretResult::RuntimeError);
+        }
+    }
 }